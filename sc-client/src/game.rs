@@ -1,202 +1,245 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::cmp::{min, max};
+use std::collections::{HashSet, VecDeque};
 use std::net::{SocketAddr, UdpSocket};
 use crate::net::{NetProcessResult, NetState};
 use raylib::prelude::*;
 use sc_types::*;
+use sc_types::agent::Agent;
+use sc_types::route::route;
 use sc_types::shapes::*;
-extern crate rmp_serde as rmps;
-use rand::*;
+use sc_types::sim::*;
 
 use crate::util::*;
 use crate::types::*;
 
-use sc_types::constants::*;
-
 use crate::render::Renderer;
-
-fn blink_unit(unit: &mut Unit) -> () {
-    unit.blinking.iter_mut().for_each(|b| *b = false);
-    if (unit.path[0] - unit.pos).length() < BLINK_RANGE {
-        let mut acc = (unit.path[0] - unit.pos).length();
-        let mut p0 = unit.path.pop_front().unwrap();
-        while !unit.path.is_empty() {
-            let p1 = *unit.path.front().unwrap();
-            let l = (p1 - p0).length();
-            if l + acc >= BLINK_RANGE {
-                unit.pos = p0.lerp(p1, (BLINK_RANGE - acc)/l);
-                return;
-            }
-            acc += l;
-            p0 = p1;
-            unit.path.pop_front();
-        }
-        unit.pos = p0;
-    } else {
-        unit.pos += (unit.path[0] - unit.pos).normalized().scale_by(BLINK_RANGE)
-    }
+use crate::templates::{Templates, TEMPLATE_SLOTS};
+
+// How far ahead of a unit, in tiles, a reroute branches off its path. Gives the command time to arrive before the
+// unit gets there.
+static REROUTE_LEAD: f32 = 2.0;
+// what render draws units as, cube_size and cube_z_offset in constants.json
+static UNIT_CUBE_SIZE: f32 = 0.5;
+static UNIT_CUBE_Z: f32 = 0.5;
+// pixels the mouse can move and still count as a click
+static CLICK_D: f32 = 4.0;
+// seconds
+static DOUBLE_CLICK_TIME: f64 = 0.3;
+
+// (Unit::id, unit) of the selected units in spawn order
+fn selected_units(game_state: &GameState) -> Vec<(usize, Unit)> {
+    game_state.units(game_state.p_id).iter()
+        .filter(|u| game_state.selection.contains(&Selection::Unit(u.id)))
+        .map(|u| (u.id, u.clone()))
+        .collect()
 }
 
-fn move_unit(unit: &mut Unit) -> () {
-    let speed = unit.speed();
-    unit.pos =
-        if (unit.path[0] - unit.pos).length() < speed {
-            // FIXME don't slow down on turns
-            unit.path[0]
-        } else {
-            unit.pos + (unit.path[0] - unit.pos).normalized().scale_by(speed)
-        };
+// Enemy interceptions we can see and blocked tiles
+fn avoid_tiles(game_state: &GameState) -> Vec<Vector2> {
+    let p_id = game_state.p_id;
+    let mut avoid: Vec<Vector2> = game_state.visible_interceptions(p_id).iter().filter(|i| game_state.enemies(i.player_id, p_id)).flat_map(|i| i.tiles()).collect();
+    avoid.extend(game_state.map.blocked.iter());
+    avoid
+}
 
-    if unit.pos == unit.path[0] {
-        unit.path.pop_front();
-    }
+// Cheapest way to finish the path being drawn, through the chosen bounties and around enemy interceptions and blocked tiles
+pub fn suggested_route(game_state: &GameState, path: &VecDeque<Vector2>, waypoints: &Vec<Vector2>) -> Option<VecDeque<Vector2>> {
+    let p_id = game_state.p_id;
+    let last = path[path.len() - 1];
+    let start_dir = if path.len() >= 2 { Some(last - path[path.len() - 2]) } else { None };
+    let avoid = avoid_tiles(game_state);
+    route(&game_state.map.area, last, start_dir, game_state.map.station(p_id), waypoints, &avoid).map(|r| {
+        let mut full = path.clone();
+        full.extend(r.into_iter().skip(1));
+        full
+    })
 }
 
-fn move_units(units: &mut Vec<Unit>) {
-    units.iter_mut().for_each(|unit|
-        match unit.blinking {
-            Some(true) => blink_unit(unit),
-            _ => move_unit(unit)
-        }
-    );
+// route() searches the whole board, so the routes previewed while drawing are only redone when what they
+// depend on changed instead of on every render frame
+#[derive(Default)]
+pub struct RouteCache {
+    // path, waypoints and avoid tiles suggested was computed for
+    suggested_key: Option<(VecDeque<Vector2>, Vec<Vector2>, Vec<Vector2>)>,
+    pub suggested: Option<VecDeque<Vector2>>,
+    // unit position and path, target and avoid tiles blink was computed for
+    blink_key: Option<(Vector2, VecDeque<Vector2>, Vector2, Vec<Vector2>)>,
+    pub blink: Option<VecDeque<Vector2>>,
 }
 
-fn apply_updates(game_state: &mut GameState, updates: [&Vec<GameCommand>; 2], p_id: usize, frame: i32) {
-    for i in 0..=1 {
-        for u in updates[i] {
-            let units = if p_id == i { &mut game_state.my_units } else { &mut game_state.other_units };
-            match u {
-                GameCommand::Blink(BlinkCommand { u_id }) => {
-                    if *u_id < units.len() {
-                        units[*u_id].blink_cooldown = units[*u_id].cooldown();
-                        units[*u_id].blinking = Some(true);
+impl RouteCache {
+    pub fn update(self: &mut Self, game_state: &GameState, mouse_state: &MouseState, mouse_tile: Vector2) {
+        match mouse_state {
+            MouseState::Path(path, _, waypoints, ..) => {
+                let key = (path.clone(), waypoints.clone(), avoid_tiles(game_state));
+                if self.suggested_key.as_ref() != Some(&key) {
+                    self.suggested = suggested_route(game_state, path, waypoints);
+                    self.suggested_key = Some(key);
+                }
+            },
+            MouseState::Blink(u_id, _) => {
+                if let Some(u) = game_state.unit(game_state.p_id, *u_id) {
+                    let key = (u.pos, u.path.clone(), mouse_tile, avoid_tiles(game_state));
+                    if self.blink_key.as_ref() != Some(&key) {
+                        self.blink = blink_path(game_state, u, mouse_tile);
+                        self.blink_key = Some(key);
                     }
-                },
-                GameCommand::Spawn(SpawnMsgCommand { path, player_id }) => {
-                    units.push(Unit {
-                        dead: false,
-                        player_id: *player_id,
-                        pos: path[0],
-                        path: path.clone(),
-                        blinking: None,
-                        blink_cooldown: 0,
-                        carrying_bounty: HashMap::new(),
-                    });
-                    game_state.spawn_cooldown[*player_id] = MSG_COOLDOWN;
-                    game_state.lumber[*player_id] -= path_lumber_cost(path);
-                },
-                GameCommand::Intercept(InterceptCommand { pos }) => {
-                    game_state.interceptions.push(Interception { pos: pos.clone(), start_frame: frame, player_id: i });
-                    game_state.gold[i] -= INTERCEPT_COST;
-                },
-                GameCommand::BuyUpgrade(u) => {
-                    game_state.upgrades[i].insert(*u);
-                    game_state.gold[i] -= u.cost();
-                },
-                GameCommand::BuyItem(item) => {
-                    game_state.items[i].entry(*item).and_modify(|e| *e += 1).or_insert(1);
-                    game_state.gold[i] -= item.cost();
                 }
-            }
+            },
+            _ => {}
         }
     }
+}
 
-    for intercept in &mut game_state.interceptions {
-        if frame - intercept.start_frame >= INTERCEPT_DELAY as i32 {
-            let other_units = if p_id == intercept.player_id { &mut game_state.other_units } else { &mut game_state.my_units };
-            for unit in other_units.iter_mut() {
-                // Have to check unit.dead to avoid double counting interception kills (If 2 interceptions kill the same unit on the same frame)
-                if !unit.dead {
-                    if rounded(unit.pos) == intercept.pos {
-                        unit.dead = true;
-                        game_state.intercepted[intercept.player_id] += 1;
-                    }
-                }
+// Whole tiles on what is left of the unit's path, with the index in unit.path of the corner that comes after each
+fn remaining_tiles(unit: &Unit) -> Vec<(Vector2, usize)> {
+    let mut out = vec![];
+    let mut prev = rounded(unit.pos);
+    for next in unit.path.iter() {
+        let d = *next - prev;
+        let n = d.x.abs().max(d.y.abs()) as i32;
+        for s in 0..=n {
+            let t = if n == 0 { prev } else { rounded(prev + d.scale_by(s as f32/n as f32)) };
+            if let Some(k) = join_index(unit, t) {
+                out.push((t, k));
             }
         }
+        prev = *next;
     }
-    game_state.interceptions.retain(|i| (frame - i.start_frame) < INTERCEPT_EXPIRY + INTERCEPT_DELAY);
-    reap(game_state);
-    game_state.other_units.retain(|u| !u.dead);
+    out
 }
 
-fn apply_bounties(game_state: &mut GameState, p_id: usize, bounties: HashMap<BountyEnum, i32>) {
-    for (b_type, amt) in bounties.iter() {
-        match *b_type {
-            BountyEnum::Fuel => { game_state.fuel[p_id] += *amt },
-            BountyEnum::Gold => { game_state.gold[p_id] += *amt as f32 },
-            BountyEnum::Lumber => { game_state.lumber[p_id] += *amt },
-            _ => {}
+// Path for a Target::Blink to dest: the cheapest way back onto the unit's old path, then the rest of it
+pub fn blink_path(game_state: &GameState, unit: &Unit, dest: Vector2) -> Option<VecDeque<Vector2>> {
+    let tiles = remaining_tiles(unit);
+    let goals: Vec<Vector2> = tiles.iter().map(|(t, _)| *t).collect();
+    let mut full = route(&game_state.map.area, dest, None, &goals, &[], &avoid_tiles(game_state))?;
+    let end = full[full.len() - 1];
+    let k = tiles.iter().find(|(t, _)| *t == end)?.1;
+    full.extend(unit.path.iter().skip(k));
+    Some(full)
+}
+
+// The tile REROUTE_LEAD ahead of the unit on its path, or its last point if it's closer than that
+fn reroute_start(unit: &Unit) -> Vector2 {
+    let mut left = REROUTE_LEAD;
+    let mut prev = unit.pos;
+    for next in unit.path.iter() {
+        let d = (*next - prev).length();
+        if d >= left {
+            return rounded(prev + (*next - prev).scale_by(left/d));
         }
+        left -= d;
+        prev = *next;
     }
+    prev
 }
 
-fn same_tile(a: Vector2, b: Vector2) -> bool {
-    a.x.round() == b.x.round() && a.y.round() == b.y.round()
+// Corner that joins a and b with axis aligned segments, None if they already line up
+fn elbow(a: Vector2, b: Vector2, y_first: bool) -> Option<Vector2> {
+    if a.x == b.x || a.y == b.y {
+        None
+    } else if y_first {
+        Some(Vector2::new(a.x, b.y))
+    } else {
+        Some(Vector2::new(b.x, a.y))
+    }
 }
 
-fn deliver_messages(game_state: &mut GameState, p_id: usize) {
-    let other_id = (p_id + 1) % 2;
+// Drops repeated points and points in the middle of a straight line, so path_lumber_cost never sees a zero length segment
+fn simplify_path(path: &VecDeque<Vector2>) -> VecDeque<Vector2> {
+    let mut out: VecDeque<Vector2> = VecDeque::new();
+    for p in path.iter() {
+        if out.back() == Some(p) {
+            continue;
+        }
+        if out.len() >= 2 {
+            let (a, b) = (out[out.len() - 2], out[out.len() - 1]);
+            if (b - a).normalized() == (*p - b).normalized() {
+                out.pop_back();
+            }
+        }
+        out.push_back(*p);
+    }
+    out
+}
 
-    let num_my_units = game_state.my_units.len() as i32;
-    let num_other_units = game_state.other_units.len() as i32;
+// path with point i moved to to, adding corners so its segments stay axis aligned
+pub fn moved_point(path: &VecDeque<Vector2>, i: usize, to: Vector2, y_first: bool) -> VecDeque<Vector2> {
+    let mut out: VecDeque<Vector2> = path.iter().take(i).cloned().collect();
+    out.extend(elbow(path[i - 1], to, y_first));
+    out.push_back(to);
+    if let Some(next) = path.get(i + 1) {
+        out.extend(elbow(to, *next, y_first));
+    }
+    out.extend(path.iter().skip(i + 1));
+    simplify_path(&out)
+}
 
-    let my_bounties = game_state.my_units.iter_mut().filter(|u| station(u.player_id).iter().any(|s| same_tile(u.pos, *s)))
-        .map(|u| { u.dead = true; u }).fold(HashMap::new(), |acc, e| hm_add(acc, &e.carrying_bounty));
-    apply_bounties(game_state, p_id, my_bounties);
-    reap(game_state);
-    let other_bounties = game_state.other_units.iter_mut().filter(|u| station(u.player_id).iter().any(|s| same_tile(u.pos, *s)))
-        .map(|u| { u.dead = true; u }).fold(HashMap::new(), |acc, e| hm_add(acc, &e.carrying_bounty));
-    apply_bounties(game_state, other_id, other_bounties);
-    game_state.other_units.retain(|u| !u.dead);
+// Index the point would have if it was inserted into the segment under p, None if p isn't strictly inside one
+fn segment_insert_index(path: &VecDeque<Vector2>, p: Vector2) -> Option<usize> {
+    path.iter().zip(path.iter().skip(1)).position(|(a, b)| {
+        let inside = |v: f32, s: f32, e: f32| v > s.min(e) && v < s.max(e);
+        (a.x == b.x && p.x == a.x && inside(p.y, a.y, b.y)) || (a.y == b.y && p.y == a.y && inside(p.x, a.x, b.x))
+    }).map(|j| j + 1)
+}
 
-    game_state.fuel[p_id] = min(START_FUEL, game_state.fuel[p_id] + (num_my_units - game_state.my_units.len() as i32) * MSG_FUEL);
-    game_state.fuel[other_id] = min(START_FUEL, game_state.fuel[other_id] + (num_other_units - game_state.other_units.len() as i32) * MSG_FUEL);
+// Same checks drawing a path does, for edited paths
+fn editable_path(game_state: &GameState, path: &VecDeque<Vector2>) -> bool {
+    path.iter().all(|p| game_state.map.area.contains_point(p)) &&
+        path.iter().zip(path.iter().skip(1)).all(|(a, b)| (a.x == b.x || a.y == b.y) && !game_state.map.crosses_blocked(a, b))
+}
 
-    game_state.gold[p_id] += (num_my_units - game_state.my_units.len() as i32) as f32 * MSG_DELIVERY_GOLD_BOUNTY;
-    game_state.gold[other_id] += (num_other_units - game_state.other_units.len() as i32) as f32 * MSG_DELIVERY_GOLD_BOUNTY;
+// F1-F9 are the template slots
+fn template_slot(k: KeyboardKey) -> Option<usize> {
+    let keys = [KeyboardKey::KEY_F1, KeyboardKey::KEY_F2, KeyboardKey::KEY_F3, KeyboardKey::KEY_F4, KeyboardKey::KEY_F5,
+        KeyboardKey::KEY_F6, KeyboardKey::KEY_F7, KeyboardKey::KEY_F8, KeyboardKey::KEY_F9];
+    keys.iter().take(TEMPLATE_SLOTS).position(|key| *key == k)
 }
 
-fn tick(game_state: &mut GameState) {
-    for u in game_state.my_units.iter_mut().chain(game_state.other_units.iter_mut()) {
-        u.blink_cooldown = max(0, u.blink_cooldown - 1);
+fn save_template(templates: &mut Templates, p_id: usize, slot: usize, path: &VecDeque<Vector2>) {
+    if path.len() >= 2 {
+        templates.error = templates.set(p_id, slot, path).err();
     }
+}
 
-    game_state.fuel.iter_mut().for_each(|f| *f -= FUEL_LOSS);
-    game_state.gold.iter_mut().for_each(|g| *g += PASSIVE_GOLD_GAIN);
-    game_state.spawn_cooldown.iter_mut().for_each(|s| *s = max(*s - 1, 0));
+// The shop is the station's panel
+pub fn shop_open(game_state: &GameState) -> bool {
+    game_state.sub_selection == Some(SubSelection::Station)
 }
 
-fn selected_units(game_state: &GameState) -> Vec<(usize, Unit)> {
-    let mut out = vec![];
-    for s in &game_state.selection {
-        if let Selection::Unit(u_id) = s {
-            if *u_id < game_state.my_units.len() {
-                out.push((*u_id, game_state.my_units[*u_id].clone()))
-            }
-        }
-    }
-    out
+// Digit keys are the control groups
+fn group_digit(k: KeyboardKey) -> Option<usize> {
+    let keys = [KeyboardKey::KEY_ZERO, KeyboardKey::KEY_ONE, KeyboardKey::KEY_TWO, KeyboardKey::KEY_THREE, KeyboardKey::KEY_FOUR,
+        KeyboardKey::KEY_FIVE, KeyboardKey::KEY_SIX, KeyboardKey::KEY_SEVEN, KeyboardKey::KEY_EIGHT, KeyboardKey::KEY_NINE];
+    keys.iter().take(CONTROL_GROUPS).position(|key| *key == k)
 }
 
-fn reap(game_state: &mut GameState) {
-    let mut out = HashSet::new();
-    for s in &game_state.selection {
-        if let Selection::Unit(selection_uid) = s {
-            if !game_state.my_units[*selection_uid].dead {
-                let mut count_dead = 0;
-                for i in 0..*selection_uid {
-                    if game_state.my_units[i].dead {
-                        count_dead += 1;
-                    }
-                }
-                out.insert(Selection::Unit(*selection_uid - count_dead));
+// Bounding box in clip space of the cube a unit is drawn as
+fn unit_clip_rect(pos: Vector2, iso_proj: Matrix) -> Rect<f32> {
+    let h = UNIT_CUBE_SIZE/2.0;
+    let mut corners = vec![];
+    for dx in [-h, h] {
+        for dy in [-h, h] {
+            for dz in [-h, h] {
+                let v = Vector4::new(pos.x + dx, pos.y + dy, UNIT_CUBE_Z + dz, 1.0).transform(iso_proj);
+                corners.push(Vector2::new(v.x, v.y));
             }
-        } else {
-            out.insert(*s);
         }
     }
-    game_state.selection = out;
+    let min_x = corners.iter().map(|c| c.x).fold(f32::INFINITY, f32::min);
+    let min_y = corners.iter().map(|c| c.y).fold(f32::INFINITY, f32::min);
+    let max_x = corners.iter().map(|c| c.x).fold(f32::NEG_INFINITY, f32::max);
+    let max_y = corners.iter().map(|c| c.y).fold(f32::NEG_INFINITY, f32::max);
+    Rect { x: min_x, y: min_y, w: max_x - min_x, h: max_y - min_y }
+}
+
+// Drops units that died from the selection and the control groups, and moves the sub selection off them if
+// none are left
+fn reap_selection(game_state: &mut GameState) {
+    let alive: HashSet<Selection> = game_state.units(game_state.p_id).iter().map(|u| Selection::Unit(u.id)).collect();
+    let keep = |s: &Selection| if let Selection::Unit(_) = s { alive.contains(s) } else { true };
+    game_state.selection.retain(keep);
+    game_state.groups.iter_mut().for_each(|g| g.retain(keep));
     let mut choices = vec![];
     if game_state.selection.iter().any(|s| if let Selection::Unit(_) = s { true } else { false }) {
         choices.push(SubSelection::Unit);
@@ -212,121 +255,22 @@ fn reap(game_state: &mut GameState) {
             game_state.sub_selection = if choices.is_empty() { None } else { Some(choices[0]) };
         }
     }
-    game_state.my_units.retain(|u| !u.dead);
-}
-
-fn no_hmap_units(units: &Vec<Unit>) -> Vec<Unit> {
-    units.iter().map(|u| Unit { carrying_bounty: HashMap::new(), ..u.clone() }).collect()
 }
 
-fn serialize_state(game_state: &GameState, p_id: usize) -> Result<Vec<u8>, rmps::encode::Error> {
-    let mut v;
-    // FIXME serialize units.carrying_bounty
-    if p_id == 0 {
-        v = rmp_serde::encode::to_vec(&no_hmap_units(&game_state.my_units))?;
-        v.append(&mut rmp_serde::encode::to_vec(&no_hmap_units(&game_state.other_units))?);
+// Points the sub selection at what is selected now
+fn update_sub_selection(game_state: &mut GameState) {
+    if game_state.selection.iter().any(|s| if let Selection::Unit(_) = s { true } else { false }) {
+        game_state.sub_selection = Some(SubSelection::Unit);
+    } else if game_state.selection.contains(&Selection::Station) {
+        game_state.sub_selection = Some(SubSelection::Station);
     } else {
-        v = rmp_serde::encode::to_vec(&no_hmap_units(&game_state.other_units))?;
-        v.append(&mut rmp_serde::encode::to_vec(&no_hmap_units(&game_state.my_units))?);
-    }
-    v.append(&mut rmp_serde::encode::to_vec(&game_state.fuel)?);
-    v.append(&mut rmp_serde::encode::to_vec(&game_state.intercepted)?);
-    v.append(&mut rmp_serde::encode::to_vec(&game_state.gold)?);
-    // FIXME serialize upgrades and items correctly (easiest might be to convert to sorted vec and serialize)
-    let upg: Vec<usize> = game_state.upgrades.iter().map(|hs| hs.len()).collect();
-    v.append(&mut rmp_serde::encode::to_vec(&upg)?);
-    v.append(&mut rmp_serde::encode::to_vec(&game_state.bounties)?);
-    // FIXME serialize game_state.next_bounty
-    Ok(v)
-}
-
-fn bounty_counts(bounties: &Vec<Bounty>) -> Vec<(BountyEnum, usize)> {
-    let mut out = vec![];
-    for b_type in [BountyEnum::Blink, BountyEnum::Fuel, BountyEnum::Gold, BountyEnum::Lumber] {
-        out.push((b_type, bounties.iter().filter(|b| b.type_ == b_type).count()));
-    }
-    out
-}
-
-fn add_bounty(game_state: &mut GameState) {
-    let rng = &mut game_state.rng;
-    if game_state.spawn_bounties {
-        let counts = bounty_counts(&game_state.bounties);
-        let existing_dist: Vec<(BountyEnum, f32)> = if game_state.bounties.is_empty() {
-                vec![(BountyEnum::Blink, 0.25), (BountyEnum::Fuel, 0.25), (BountyEnum::Lumber, 0.25), (BountyEnum::Gold, 0.25)]
-            } else {
-                counts.iter().map(|(k, v)| (*k, *v as f32/game_state.bounties.len() as f32)).collect()
-            };
-        let mut p_dist: Vec<(BountyEnum, f32)> = vec![];
-        for (k, v) in existing_dist {
-            p_dist.push((k, (1f32 - v)/3f32));
-        }
-        let r = rng.gen_range(0..100);
-        let (m_t_to_spawn, _) = p_dist.iter().fold((None, r), |(m_out, acc_r), (b_type, p)| {
-            match m_out {
-                Some(out) => (Some(out), acc_r),
-                None => {
-                    if acc_r < (p * 100f32).round() as i32 {
-                        (Some(*b_type), acc_r)
-                    } else {
-                        (None, acc_r - (p * 100f32).round() as i32)
-                    }
-                }
-            }
-        });
-
-        let t_to_spawn = m_t_to_spawn.unwrap_or(p_dist[p_dist.len() - 1].0);
-
-        let mut b = Vector2::new(rng.gen_range(PLAY_AREA.x..(PLAY_AREA.x + PLAY_AREA.w)) as f32, rng.gen_range(PLAY_AREA.y..(PLAY_AREA.y + PLAY_AREA.h)) as f32);
-        while same_tile(*ship(0), b) ||
-              same_tile(*ship(1), b) ||
-              station(0).iter().any(|s| same_tile(*s, b)) ||
-              station(1).iter().any(|s| same_tile(*s, b)) ||
-                game_state.bounties.iter().any(|existing_b| same_tile(existing_b.pos, b)) {
-            b = Vector2::new(rng.gen_range(PLAY_AREA.x..(PLAY_AREA.x + PLAY_AREA.w)) as f32, rng.gen_range(PLAY_AREA.y..(PLAY_AREA.y + PLAY_AREA.h)) as f32);
-        }
-        game_state.bounties.push(Bounty { type_: t_to_spawn, amount: t_to_spawn.amount(), pos: b });
-    } 
-}
-
-fn collide_bounties(game_state: &mut GameState) {
-    let pack_bounty = |m_unit: Option<&mut Unit>, b: &Bounty| {
-        if let Some(unit) = m_unit {
-            if b.type_ == BountyEnum::Blink {
-                unit.blink_cooldown = 0;
-                if unit.blinking.is_none() {
-                    unit.blinking = Some(false);
-                }
-            }
-            unit.carrying_bounty.entry(b.type_).and_modify(|e| *e += b.amount).or_insert(b.amount);
-        } 
-    };
-
-    for b in &game_state.bounties {
-        let m_mine = game_state.my_units.iter_mut().find(|u| same_tile(u.pos, b.pos));
-        let m_other = game_state.other_units.iter_mut().find(|u| same_tile(u.pos, b.pos));
-        pack_bounty(m_mine, b);
-        pack_bounty(m_other, b);
+        game_state.sub_selection = Some(SubSelection::Ship);
     }
-
-    // PERF loop only once
-    game_state.bounties.retain(|b| !game_state.my_units.iter().any(|u| same_tile(u.pos, b.pos)) &&
-        !game_state.other_units.iter().any(|u| same_tile(u.pos, b.pos)))
 }
 
-pub fn path_lumber_cost(path: &VecDeque<Vector2>) -> i32 {
-    if path.len() <= 1 {
-        0
-    } else {
-        max(0, path.iter().skip(2).fold((0, path[1], (path[1] - path[0]).normalized()), |(acc, last, dir), e| {
-            let new_dir = (*e - last).normalized();
-            if new_dir == dir || new_dir == Vector2::zero() {
-                (acc, *e, dir)
-            } else {
-                (acc + 1, *e, new_dir)
-            }
-        }).0 - MSG_FREE_LUMBER)
-    }
+fn select_only(game_state: &mut GameState, s: Selection, sub: SubSelection) {
+    game_state.selection = HashSet::from([s]);
+    game_state.sub_selection = Some(sub);
 }
 
 pub fn set_non_fullscreen_window_size(rl: &mut RaylibHandle) {
@@ -348,18 +292,50 @@ pub fn set_non_fullscreen_window_size(rl: &mut RaylibHandle) {
     rl.set_window_position(mon_width/8, mon_height/8);
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum PathKind {
+    Message,
+    // sent as Item::Decoy
+    Decoy,
+    // new route for our unit u_id, sent as a Target::Move TargetCommand
+    Reroute(usize),
+}
+
 pub enum MouseState {
     Drag(Vector2),
-    Path(VecDeque<Vector2>, bool),
-    Intercept,
+    // path so far, y_first, bounties the suggested route has to go through, what the path is for, index of the point
+    // held down and the screen position it was pressed at, the position is dropped once the mouse moves CLICK_D away
+    // and the hold becomes a drag
+    Path(VecDeque<Vector2>, bool, Vec<Vector2>, PathKind, Option<(usize, Option<Vector2>)>),
+    // the abilities hold on to the path being drawn when they were started, it comes back once they are cast or cancelled
+    Intercept(Option<Box<MouseState>>),
+    Grapple(Option<Box<MouseState>>),
+    // aiming a Target::Blink for our unit u_id
+    Blink(usize, Option<Box<MouseState>>),
     WaitReleaseLButton,
     None
 }
 
+impl MouseState {
+    // The path set aside while aiming an ability
+    pub fn resume(self: &Self) -> Option<&MouseState> {
+        match self {
+            MouseState::Intercept(resume) | MouseState::Grapple(resume) | MouseState::Blink(_, resume) => resume.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// What to go back to once an ability is done with
+fn resumed(resume: &mut Option<Box<MouseState>>, or: MouseState) -> MouseState {
+    resume.take().map(|m| *m).unwrap_or(or)
+}
+
 pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mut bool, borderless: &mut bool,
     rl: &mut RaylibHandle, mouse_state: &mut MouseState, net: &mut NetState,
     frame_counter: &mut i32, socket: &UdpSocket, m_server: &Option<SocketAddr>, seq_state: &mut SeqState, frame_rate: u32,
-    game_ps: &mut TimeWindowAvg) -> ClientState {
+    game_ps: &mut TimeWindowAvg, agents: &mut Vec<(usize, Box<dyn Agent>)>, templates: &mut Templates, clock: &mut SimClock,
+    last_click: &mut Option<(f64, Vector2)>) -> ClientState {
     let p_id = game_state.p_id;
     let raw_mouse_position = rl.get_mouse_position();
     let screen_width =  rl.get_screen_width() as f64;
@@ -369,8 +345,17 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
     let iso_proj = Renderer::iso_proj(screen_width, screen_height, *zoom);
     let mouse_tile = Vector2::new(mouse_position.x.round(), mouse_position.y.round());
     let mut start_message_path = false;
+    let mut start_decoy_path = false;
+    let mut m_reroute = None;
+    let mut m_blink_target = None;
     let mut cancel = false;
     let mut start_intercept = false;
+    let mut start_grapple = false;
+    let mut toggle_waypoint = false;
+    let mut accept_route = false;
+    let mut undo_point = false;
+    let mut m_save_slot = None;
+    let mut m_recall_slot = None;
     *screen_changed = false;
     loop {
         match rl.get_key_pressed() {
@@ -395,10 +380,12 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                             *screen_changed = true;
                         }
                     }
-                    KeyboardKey::KEY_ONE => {
-                        game_state.selection = HashSet::new();
-                        game_state.selection.insert(Selection::Ship);
-                        game_state.sub_selection = Some(SubSelection::Ship);
+                    KeyboardKey::KEY_S => {
+                        if shop_open(game_state) {
+                            select_only(game_state, Selection::Ship, SubSelection::Ship);
+                        } else {
+                            select_only(game_state, Selection::Station, SubSelection::Station);
+                        }
                     },
                     KeyboardKey::KEY_TAB => {
                         if let Some(subsel) = game_state.sub_selection {
@@ -421,7 +408,7 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                         }
                     },
                     KeyboardKey::KEY_W => {
-                        if game_state.gold[p_id] < INTERCEPT_COST {
+                        if game_state.gold[p_id] < game_state.rules.intercept_cost {
                             // TODO show ui report error
                         } else {
                             start_intercept = true;
@@ -429,11 +416,22 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                     },
                     KeyboardKey::KEY_ESCAPE => {
                         match mouse_state {
-                            MouseState::Path(_, _) => { cancel = true }
-                            MouseState::Intercept => { cancel = true }
+                            MouseState::Path(..) => { cancel = true }
+                            MouseState::Intercept(_) => { cancel = true }
+                            MouseState::Grapple(_) => { cancel = true }
+                            MouseState::Blink(..) => { cancel = true }
                             _ => {}
                         }
                     },
+                    KeyboardKey::KEY_B => {
+                        toggle_waypoint = true;
+                    },
+                    KeyboardKey::KEY_R => {
+                        accept_route = true;
+                    },
+                    KeyboardKey::KEY_BACKSPACE => {
+                        undo_point = true;
+                    },
                     KeyboardKey::KEY_Z => {
                         for (u_id, u) in selected_units(&game_state) {
                             if u.blink_cooldown <= 0 && u.blinking.is_some() {
@@ -441,6 +439,69 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                             }
                         }
                     },
+                    KeyboardKey::KEY_X => {
+                        let selected = selected_units(&game_state);
+                        if selected.len() == 1 && selected[0].1.blink_cooldown <= 0 && selected[0].1.blinking == Some(false) {
+                            m_blink_target = Some(selected[0].0);
+                        }
+                    },
+                    KeyboardKey::KEY_T => {
+                        let selected = selected_units(&game_state);
+                        if selected.len() == 1 {
+                            m_reroute = Some(selected[0].0);
+                        }
+                    },
+                    KeyboardKey::KEY_D => {
+                        if game_state.item_count(p_id, Item::Decoy) > 0 {
+                            start_decoy_path = true
+                        }
+                    },
+                    KeyboardKey::KEY_E => {
+                        // one shield per selected unit while they last
+                        let shields = game_state.item_count(p_id, Item::Shield) as usize;
+                        for (u_id, _) in selected_units(&game_state).into_iter().filter(|(_, u)| u.shield <= 0).take(shields) {
+                            net.queue_command(GameCommand::UseItem(ItemUse::Shield { u_id }));
+                        }
+                    },
+                    KeyboardKey::KEY_F => {
+                        if game_state.item_count(p_id, Item::FuelCanister) > 0 {
+                            net.queue_command(GameCommand::UseItem(ItemUse::FuelCanister));
+                        }
+                    },
+                    KeyboardKey::KEY_V => {
+                        if game_state.item_count(p_id, Item::Reveal) > 0 {
+                            net.queue_command(GameCommand::UseItem(ItemUse::Reveal));
+                        }
+                    },
+                    k if group_digit(k).is_some() => {
+                        let g = group_digit(k).unwrap();
+                        if rl.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) || rl.is_key_down(KeyboardKey::KEY_RIGHT_CONTROL) {
+                            game_state.groups[g] = game_state.selection.clone();
+                        } else if rl.is_key_down(KeyboardKey::KEY_LEFT_SHIFT) || rl.is_key_down(KeyboardKey::KEY_RIGHT_SHIFT) {
+                            game_state.selection = game_state.selection.union(&game_state.groups[g]).cloned().collect();
+                            update_sub_selection(game_state);
+                        } else if !game_state.groups[g].is_empty() {
+                            game_state.selection = game_state.groups[g].clone();
+                            update_sub_selection(game_state);
+                        } else if k == KeyboardKey::KEY_ONE {
+                            // 1 selects the ship until a group is bound to it
+                            select_only(game_state, Selection::Ship, SubSelection::Ship);
+                        }
+                    },
+                    k if template_slot(k).is_some() => {
+                        if rl.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) || rl.is_key_down(KeyboardKey::KEY_RIGHT_CONTROL) {
+                            m_save_slot = template_slot(k);
+                        } else {
+                            m_recall_slot = template_slot(k);
+                        }
+                    },
+                    KeyboardKey::KEY_G => {
+                        if game_state.gold[p_id] < game_state.rules.grapple_cost || game_state.grapple_cooldown[p_id] > 0 {
+                            // TODO show ui report error
+                        } else {
+                            start_grapple = true;
+                        }
+                    },
                     _ => {}
                 }
             }
@@ -448,15 +509,76 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
         }
     }
 
+    // casting from the middle of a path, not while a point of it is being dragged
+    if let MouseState::Path(_, _, _, _, None) = mouse_state {
+        if start_intercept || m_blink_target.is_some() || start_grapple {
+            let resume = Some(Box::new(std::mem::replace(mouse_state, MouseState::None)));
+            rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_CROSSHAIR);
+            *mouse_state = if start_intercept {
+                MouseState::Intercept(resume)
+            } else if let Some(u_id) = m_blink_target {
+                MouseState::Blink(u_id, resume)
+            } else {
+                MouseState::Grapple(resume)
+            };
+            // the keys were used up starting the ability
+            start_intercept = false;
+            m_blink_target = None;
+            start_grapple = false;
+        }
+    }
+
     match mouse_state {
         MouseState::None => {
-            if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) {
+            // not drawing, so save the last path we sent
+            if let (Some(slot), Some(path)) = (m_save_slot, templates.last.clone()) {
+                save_template(templates, p_id, slot, &path);
+            }
+            let m_recalled = m_recall_slot.and_then(|slot| templates.get(p_id, slot));
+            let m_shop_row = if shop_open(game_state) {
+                Renderer::shop_layout(screen_width, screen_height).into_iter().find(|(_, r)| r.contains_point(&raw_mouse_position))
+            } else {
+                None
+            };
+            if let (Some((item, _)), true) = (m_shop_row, rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT)) {
+                let command = item.buy_command();
+                if validate_command(game_state, p_id, &command) {
+                    net.queue_command(command);
+                }
+                // TODO show ui error can't afford/already owned
+                *mouse_state = MouseState::WaitReleaseLButton;
+            } else if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) {
                 *mouse_state = MouseState::Drag(raw_mouse_position);
-            } else if start_message_path {
-                *mouse_state = MouseState::Path(VecDeque::from(vec![*ship(p_id)]), true);
+            } else if let Some(path) = m_recalled {
+                // complete templates go out right away, partial ones are picked up where they left off
+                if game_state.map.station(p_id).contains(&path[path.len() - 1]) {
+                    let command = GameCommand::Spawn(SpawnMsgCommand { player_id: p_id, path: path.clone() });
+                    if validate_command(game_state, p_id, &command) {
+                        net.queue_command(command);
+                        templates.last = Some(path);
+                    }
+                    // TODO show ui error not enough lumber/on cooldown
+                    *mouse_state = MouseState::None;
+                } else if game_state.spawn_cooldown[p_id] <= 0 {
+                    *mouse_state = MouseState::Path(path, true, vec![], PathKind::Message, None);
+                } else {
+                    *mouse_state = MouseState::None;
+                }
+            } else if let Some(u) = m_reroute.and_then(|u_id| game_state.unit(p_id, u_id)) {
+                let start = reroute_start(u);
+                *mouse_state = MouseState::Path(VecDeque::from(vec![start]), true, vec![], PathKind::Reroute(u.id), None);
+            } else if start_message_path || start_decoy_path {
+                let kind = if start_decoy_path { PathKind::Decoy } else { PathKind::Message };
+                *mouse_state = MouseState::Path(VecDeque::from(vec![*game_state.map.ship(p_id)]), true, vec![], kind, None);
             } else if start_intercept {
                 rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_CROSSHAIR);
-                *mouse_state = MouseState::Intercept;
+                *mouse_state = MouseState::Intercept(None);
+            } else if let Some(u_id) = m_blink_target {
+                rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_CROSSHAIR);
+                *mouse_state = MouseState::Blink(u_id, None);
+            } else if start_grapple {
+                rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_CROSSHAIR);
+                *mouse_state = MouseState::Grapple(None);
             } else {
                 *mouse_state = MouseState::None;
             }
@@ -470,32 +592,109 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                 let selection_size = Vector2 { x: (start_pos_clip.x - clip_mouse_position.x).abs(), y: (start_pos_clip.y - clip_mouse_position.y).abs() };
                 let selection_rect = Rect { x: selection_pos.x, y: selection_pos.y, w: selection_size.x, h: selection_size.y };
 
-                // FIXME use cube_z_offset
-                fn unit_vec4(v2: Vector2) -> Vector4 { Vector4::new(v2.x, v2.y, 0.5, 1.0) }
-                fn unit_screen_pos(v4: Vector4) -> Vector2 { Vector2::new(v4.x, v4.y) }
-                let in_box: Vec<_> = game_state.my_units.iter().enumerate().filter(|(_, u)| selection_rect.contains_point(&unit_screen_pos(unit_vec4(u.pos).transform(iso_proj)))).map(|(i, _)| Selection::Unit(i)).collect();
-                if !in_box.is_empty() {
+                // a click that lands close enough to the last one selects all our messages
+                let click = (*start_pos - raw_mouse_position).length() <= CLICK_D;
+                let now = rl.get_time();
+                let double_click = match *last_click {
+                    Some((t, p)) => click && now - t <= DOUBLE_CLICK_TIME && (p - raw_mouse_position).length() <= CLICK_D,
+                    None => false,
+                };
+                *last_click = if click && !double_click { Some((now, raw_mouse_position)) } else { None };
+
+                let my_units = game_state.units(p_id);
+                let in_box: Vec<_> = my_units.iter().filter(|u| selection_rect.collide(&unit_clip_rect(u.pos, iso_proj))).map(|u| Selection::Unit(u.id)).collect();
+                if double_click && !my_units.is_empty() {
+                    game_state.selection = my_units.iter().map(|u| Selection::Unit(u.id)).collect();
+                } else if in_box.is_empty() && game_state.map.station(p_id).contains(&mouse_tile) {
+                    game_state.selection = HashSet::from([Selection::Station]);
+                } else if !in_box.is_empty() {
                     if rl.is_key_down(KeyboardKey::KEY_LEFT_SHIFT) || rl.is_key_down(KeyboardKey::KEY_RIGHT_SHIFT) {
                         game_state.selection = game_state.selection.symmetric_difference(&HashSet::from_iter(in_box)).cloned().collect();
                     } else {
                         game_state.selection = HashSet::from_iter(in_box);
                     }
                 }
-                if game_state.selection.iter().any(|s| if let Selection::Unit(_) = s { true } else { false }) {
-                    game_state.sub_selection = Some(SubSelection::Unit);
-                } else {
-                    game_state.sub_selection = Some(SubSelection::Ship);
-                }
+                update_sub_selection(game_state);
                 *mouse_state = MouseState::None;
             }
         },
-        MouseState::Path(path, y_first) => {
-            if cancel {
+        MouseState::Path(path, y_first, waypoints, kind, dragging) => {
+            let kind = *kind;
+            let send = |path: VecDeque<Vector2>| match kind {
+                PathKind::Message => GameCommand::Spawn(SpawnMsgCommand { player_id: p_id, path }),
+                PathKind::Decoy => GameCommand::UseItem(ItemUse::Decoy(SpawnMsgCommand { player_id: p_id, path })),
+                PathKind::Reroute(u_id) => GameCommand::Target(TargetCommand { u_id, target: Target::Move, path }),
+            };
+            // decoys don't cost lumber, reroutes have to still join the unit's path
+            let affordable = |path: &VecDeque<Vector2>| match kind {
+                PathKind::Message => game_state.lumber[p_id] >= path_lumber_cost(path, &game_state.rules),
+                PathKind::Decoy => true,
+                PathKind::Reroute(_) => validate_command(game_state, p_id, &send(path.clone())),
+            };
+            // templates start at the ship
+            let is_template = !matches!(kind, PathKind::Reroute(_));
+            if let (Some(slot), true) = (m_save_slot, is_template) {
+                save_template(templates, p_id, slot, path);
+            }
+            let l_pressed = rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT);
+            let l_down = rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT);
+            // a point released before the mouse moved away from it was a click, it adds a point there like any other
+            let mut l_clicked = false;
+            if let Some((i, Some(pressed_at))) = *dragging {
+                if !l_down {
+                    *dragging = None;
+                    l_clicked = true;
+                } else if (pressed_at - raw_mouse_position).length() > CLICK_D {
+                    *dragging = Some((i, None));
+                }
+            }
+            let ctrl = rl.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) || rl.is_key_down(KeyboardKey::KEY_RIGHT_CONTROL);
+            // the ship can't be moved
+            let m_grab = path.iter().skip(1).position(|p| *p == mouse_tile).map(|i| i + 1);
+            if let Some((i, pressed_at)) = *dragging {
+                if pressed_at.is_none() && !l_down {
+                    let edited = moved_point(path, i, mouse_tile, *y_first);
+                    if editable_path(game_state, &edited) {
+                        *path = edited;
+                    }
+                    // TODO show ui error path crosses blocked terrain
+                    *dragging = None;
+                }
+            } else if cancel {
                 *mouse_state = MouseState::None;
+            } else if undo_point {
+                if path.len() > 1 {
+                    path.pop_back();
+                }
+            } else if l_pressed && m_grab.is_some() {
+                *dragging = m_grab.map(|i| (i, Some(raw_mouse_position)));
+            } else if l_pressed && ctrl && segment_insert_index(path, mouse_tile).is_some() {
+                let i = segment_insert_index(path, mouse_tile).unwrap();
+                path.insert(i, mouse_tile);
+                *dragging = Some((i, None));
             } else {
-                if rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_RIGHT) {
+                if toggle_waypoint {
+                    if let Some(b) = game_state.bounties.iter().find(|b| same_tile(b.pos, mouse_tile)) {
+                        match waypoints.iter().position(|w| same_tile(*w, b.pos)) {
+                            Some(i) => { waypoints.remove(i); },
+                            None => waypoints.push(rounded(b.pos)),
+                        }
+                    }
+                } else if accept_route {
+                    match suggested_route(game_state, path, waypoints) {
+                        Some(full) if affordable(&full) => {
+                            if is_template {
+                                templates.last = Some(full.clone());
+                            }
+                            net.queue_command(send(full));
+                            *mouse_state = MouseState::None;
+                        },
+                        // TODO show ui error no route/not enough lumber
+                        _ => {}
+                    }
+                } else if rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_RIGHT) {
                     *y_first = !*y_first;
-                } else if PLAY_AREA.contains_point(&mouse_tile) && rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT) {
+                } else if game_state.map.area.contains_point(&mouse_tile) && (l_pressed || l_clicked) {
                     let p = path[path.len() - 1];
                     let m: Vector2;
                     if *y_first {
@@ -503,39 +702,75 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
                     } else {
                         m = Vector2::new(mouse_position.x.round(), p.y.round());
                     }
-                    path.push_back(m);
-                    if !(station(p_id).iter().any(|s| *s == m)) {
-                        path.push_back(Vector2::new(mouse_position.x.round(), mouse_position.y.round()));
-                    }
-                    if  station(p_id).iter().any(|s| *s == m) ||
-                        station(p_id).iter().any(|s| *s == Vector2::new(mouse_position.x.round(), mouse_position.y.round())) {
-                        if game_state.lumber[p_id] >= path_lumber_cost(&path) {
-                            net.queue_command(GameCommand::Spawn(SpawnMsgCommand { player_id: p_id, path: path.clone() }));
-                            *mouse_state = MouseState::WaitReleaseLButton;
-                        } else {
-                            // TODO show ui error not enought lumber
-                            *mouse_state = MouseState::WaitReleaseLButton;
+                    let end = Vector2::new(mouse_position.x.round(), mouse_position.y.round());
+                    let m_at_station = game_state.map.station(p_id).iter().any(|s| *s == m);
+                    if game_state.map.crosses_blocked(&p, &m) || (!m_at_station && game_state.map.crosses_blocked(&m, &end)) {
+                        // TODO show ui error path crosses blocked terrain
+                    } else {
+                        path.push_back(m);
+                        if !m_at_station {
+                            path.push_back(end);
+                        }
+                        if m_at_station || game_state.map.station(p_id).iter().any(|s| *s == end) {
+                            if affordable(path) {
+                                if is_template {
+                                    templates.last = Some(path.clone());
+                                }
+                                net.queue_command(send(path.clone()));
+                                *mouse_state = MouseState::WaitReleaseLButton;
+                            } else {
+                                // TODO show ui error not enought lumber
+                                *mouse_state = MouseState::WaitReleaseLButton;
+                            }
                         }
                     }
                 }
             }
         },
-        MouseState::Intercept => {
+        // a resumed path only adds points on a fresh press, so the click that cast doesn't reach it
+        MouseState::Intercept(resume) => {
             if cancel {
                 rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
-                *mouse_state = MouseState::None;
+                *mouse_state = resumed(resume, MouseState::None);
             } else if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) {
-                if PLAY_AREA.contains_point(&mouse_tile) &&
-                        game_state.gold[p_id] >= INTERCEPT_COST {
+                if game_state.map.area.contains_point(&mouse_tile) &&
+                        game_state.gold[p_id] >= game_state.rules.intercept_cost {
                     net.queue_command(GameCommand::Intercept(InterceptCommand { pos: mouse_tile }));
                     rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
-                    *mouse_state = MouseState::WaitReleaseLButton;
-                } else {
-                    // TODO show error if not enough gold
-                    *mouse_state = MouseState::Intercept;
+                    *mouse_state = resumed(resume, MouseState::WaitReleaseLButton);
                 }
-            } else {
-                *mouse_state = MouseState::Intercept;
+                // TODO show error if not enough gold
+            }
+        },
+        MouseState::Grapple(resume) => {
+            if cancel {
+                rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
+                *mouse_state = resumed(resume, MouseState::None);
+            } else if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) {
+                let command = GameCommand::Grapple(GrappleCommand { pos: mouse_tile });
+                if validate_command(game_state, p_id, &command) {
+                    net.queue_command(command);
+                    rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
+                    *mouse_state = resumed(resume, MouseState::WaitReleaseLButton);
+                }
+                // TODO show error if out of range
+            }
+        },
+        MouseState::Blink(u_id, resume) => {
+            let u_id = *u_id;
+            if cancel || game_state.unit(p_id, u_id).is_none() {
+                rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
+                *mouse_state = resumed(resume, MouseState::None);
+            } else if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) {
+                let m_command = game_state.unit(p_id, u_id).and_then(|u| blink_path(game_state, u, mouse_tile))
+                    .map(|path| GameCommand::Target(TargetCommand { u_id, target: Target::Blink, path }))
+                    .filter(|command| validate_command(game_state, p_id, command));
+                if let Some(command) = m_command {
+                    net.queue_command(command);
+                    rl.set_mouse_cursor(MouseCursor::MOUSE_CURSOR_DEFAULT);
+                    *mouse_state = resumed(resume, MouseState::WaitReleaseLButton);
+                }
+                // TODO show error if out of range/no way back
             }
         },
         MouseState::WaitReleaseLButton => {
@@ -547,46 +782,52 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
         }
     };
 
-    let npr = net.process(*frame_counter, &socket, &m_server, seq_state, frame_rate);
-
-    if let NetProcessResult::PeerDisconnect = npr {
-        return ClientState::Waiting;
-    }
+    // as many steps as the time since the last render frame needs, so the match runs at frame_rate however fast we draw
+    clock.tick();
+    while clock.due() && game_over(game_state).is_none() {
+        let npr = net.process(*frame_counter, &socket, &m_server, seq_state, frame_rate);
 
-    // TODO use types to make sure sent/recvd packet can't be mistaken for each other
-    if let NetProcessResult::Success(sent_pkt, recvd_pkt) = npr {
-        if game_state.bounties.len() >= 10 {
-            game_state.spawn_bounties = false;
+        if let NetProcessResult::PeerDisconnect = npr {
+            return ClientState::Waiting;
         }
-        if game_state.bounties.len() < 6 {
-            game_state.spawn_bounties = true;
-        }
-        game_ps.sample();
-        apply_updates(game_state, if p_id == 0 { [&sent_pkt, &recvd_pkt] } else { [&recvd_pkt, &sent_pkt] }, p_id, *frame_counter);
-        
-        if (*frame_counter % (3 * 60)) == 0 {
-            add_bounty(game_state);
-        }
-        move_units(&mut game_state.my_units);
-        move_units(&mut game_state.other_units);
-        deliver_messages(game_state, p_id);
-        collide_bounties(game_state);
-        tick(game_state);
-        *frame_counter += 1;
-        if *frame_counter % 60 == 0 {
-            if let Some(server) = m_server {
-                socket_send(&socket, server, &ClientPkt::StateHash { 
-                    seq: seq_state.send_seq,
-                    ack: seq_state.send_ack,
-                    hash: crc32fast::hash(&serialize_state(&game_state, p_id).unwrap()),
-                    frame: *frame_counter,
-                }).unwrap();
-                seq_state.send();
+
+        // TODO use types to make sure sent/recvd packet can't be mistaken for each other
+        if let NetProcessResult::Success(sent_pkt, mut recvd_pkts) = npr {
+            recvd_pkts[p_id] = sent_pkt;
+            game_ps.sample();
+            clock.stepping(game_state);
+            step(game_state, &recvd_pkts.iter().collect::<Vec<_>>(), *frame_counter);
+            *frame_counter += 1;
+            // In sandbox agents play the other players, online the only agent plays for us. Either way their
+            // commands go through the frame delay like ours.
+            for (a_id, agent) in agents.iter_mut() {
+                for command in agent.decide(game_state, *a_id, *frame_counter) {
+                    if *a_id == p_id {
+                        net.queue_command(command);
+                    } else {
+                        net.queue_agent_command(*a_id, command);
+                    }
+                }
             }
+            if *frame_counter % 60 == 0 {
+                if let Some(server) = m_server {
+                    socket_send(&socket, server, &ClientPkt::StateHash { 
+                        seq: seq_state.send_seq,
+                        ack: seq_state.send_ack,
+                        hash: crc32fast::hash(&serialize_state(&game_state).unwrap()),
+                        frame: *frame_counter,
+                    }).unwrap();
+                    seq_state.send();
+                }
+            }
+        } else {
+            // still waiting on the others, try again next render frame
+            break;
         }
     }
+    reap_selection(game_state);
 
-    if game_state.fuel.iter().any(|f| *f <= 0) || game_state.intercepted.iter().any(|v| *v >= KILLS_TO_WIN) {
+    if let Some(winner) = game_over(game_state) {
         if let Some(server) = m_server {
             socket_send(&socket, server, &ClientPkt::Ended { 
                 seq: seq_state.send_seq,
@@ -595,20 +836,7 @@ pub fn run_game(game_state: &mut GameState, screen_changed: &mut bool, zoom: &mu
             }).unwrap();
             seq_state.send();
         }
-
-        if game_state.intercepted.iter().all(|v| *v >= KILLS_TO_WIN) || game_state.fuel.iter().all(|f| *f <= 0) {
-            ClientState::Ended(None)
-        } else {
-            if game_state.fuel[0] <= 0 && game_state.fuel[1] > 0 {
-                ClientState::Ended(Some(1usize))
-            } else if game_state.fuel[0] > 0 && game_state.fuel[1] <= 0 {
-                ClientState::Ended(Some(0usize))
-            } else if game_state.intercepted[0] >= KILLS_TO_WIN {
-                ClientState::Ended(Some(0usize))
-            } else {
-                ClientState::Ended(Some(1usize))
-            }
-        }
+        ClientState::Ended(winner)
     } else {
         ClientState::Started
     }
@@ -4,6 +4,9 @@ use net::{handle_handshake, NetState};
 use rand_core::SeedableRng;
 use raylib::prelude::*;
 use sc_types::*;
+use sc_types::agent::{agent_from_arg, play_match, Agent};
+use sc_types::map::{Map, DEFAULT_MAP_ID};
+use sc_types::ruleset::Ruleset;
 extern crate rmp_serde as rmps;
 use rand_chacha::*;
 
@@ -12,27 +15,125 @@ mod types;
 mod render;
 mod net;
 mod game;
+mod templates;
 
 use game::*;
 use util::*;
 use types::*;
 
 use crate::render::Renderer;
+use crate::templates::Templates;
+
+static BENCH_MAX_FRAMES: i32 = 60 * 60 * 10;
+static RULESET_PATH: &str = "ruleset.json";
+
+// Online games use the ruleset sent by the server, this is for sandbox and bench
+fn load_ruleset() -> Ruleset {
+    Ruleset::load_or_default(RULESET_PATH).unwrap_or_else(|e| {
+        println!("Unable to load ruleset {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn agent_arg(s: &str, rng: ChaCha20Rng) -> Box<dyn Agent> {
+    agent_from_arg(s, rng).unwrap_or_else(|| {
+        println!("Unknown agent {}. Expected none|easy|medium|hard", s);
+        std::process::exit(1);
+    })
+}
+
+fn load_map(id: &str) -> Map {
+    Map::load(id).unwrap_or_else(|e| {
+        println!("Unable to load map {}", e);
+        std::process::exit(1);
+    })
+}
+
+// Templates are the player's own files, a broken or outdated one shouldn't keep them from playing
+fn load_templates(map_id: &str) -> Templates {
+    Templates::load(map_id).unwrap_or_else(|e| {
+        println!("Unable to load templates {}", e);
+        Templates::empty(map_id, e)
+    })
+}
+
+fn mode_arg(m_s: Option<&String>) -> Mode {
+    match m_s {
+        Some(s) => Mode::from_arg(s).unwrap_or_else(|| {
+            println!("Unknown mode {}. Expected ffa|teams", s);
+            std::process::exit(1);
+        }),
+        None => Mode::Ffa,
+    }
+}
+
+// Plays agent0 as player 0 against agent1 as every other player headlessly and prints the results
+fn bench(games: u64, agent0: &str, agent1: &str, map_id: &str, mode: Mode) {
+    let rules = load_ruleset();
+    let map = load_map(map_id);
+    let players = map.players() as u64;
+    // indexed by team
+    let mut wins = vec![0; players as usize];
+    let mut draws = 0;
+    let mut frames: i64 = 0;
+    for i in 0..games {
+        let mut agents: Vec<Box<dyn Agent>> = (0..players).map(|p| {
+            let rng = ChaCha20Rng::seed_from_u64(players * i + p);
+            agent_arg(if p == 0 { agent0 } else { agent1 }, rng)
+        }).collect();
+        let result = play_match(&mut agents, ChaCha20Rng::seed_from_u64(i), rules.clone(), map.clone(), mode, BENCH_MAX_FRAMES);
+        match result.winner {
+            Some(w) => wins[w] += 1,
+            None => draws += 1,
+        }
+        frames += result.frames as i64;
+    }
+    println!("{} vs {} over {} games", agent0, agent1, games);
+    let teams = mode.teams(players as usize);
+    let mut team_ids = teams.clone();
+    team_ids.sort();
+    team_ids.dedup();
+    for t in team_ids {
+        let members: Vec<String> = (0..teams.len()).filter(|p| teams[*p] == t).map(|p| format!("p{}", p)).collect();
+        print!("{} wins: {} ", members.join("+"), wins[t]);
+    }
+    println!("draws: {}", draws);
+    println!("avg game length: {}s", frames as f64/(games.max(1) as f64 * 60.0));
+}
 
 fn main() -> std::io::Result<()> {
     let frame_rate = 60;
 
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        println!("Usage {} server_addr|sandbox", args[0]);
+        println!("Usage {} server_addr [bot]|sandbox [opponent] [map] [mode]|bench games agent0 agent1 [map] [mode]", args[0]);
+        println!("  bot/opponent/agent: none|easy|medium|hard");
+        println!("  mode: ffa|teams");
         std::process::exit(1);
     }
 
+    if args[1] == "bench" {
+        if args.len() < 5 {
+            println!("Usage {} bench games agent0 agent1 [map] [mode]", args[0]);
+            std::process::exit(1);
+        }
+        bench(args[2].parse().expect("games should be a number"), &args[3], &args[4], args.get(5).map(|s| &s[..]).unwrap_or(DEFAULT_MAP_ID), mode_arg(args.get(6)));
+        return Ok(());
+    }
+
     let m_server;
+    // (player the agent plays, agent)
+    let mut agents: Vec<(usize, Box<dyn Agent>)>;
+    let map;
+    let mode;
     let mut state = ClientState::SendHello;
     if args[1] == "sandbox" {
         m_server = None;
         state = ClientState::Started;
+        map = load_map(args.get(3).map(|s| &s[..]).unwrap_or(DEFAULT_MAP_ID));
+        mode = mode_arg(args.get(4));
+        // we are player 0, the opponent plays everyone else
+        agents = (1..map.players()).map(|p| (p, agent_arg(args.get(2).map(|s| &s[..]).unwrap_or("medium"), ChaCha20Rng::from_entropy()))).collect();
     } else {
         let server_addr = &args[1][..];
 
@@ -44,6 +145,11 @@ fn main() -> std::io::Result<()> {
             panic!("unable to resolve server?")
         }
         m_server = Some(vec_server[0]);
+        // the player is set once the server assigns us one
+        agents = args.get(2).map(|s| (0, agent_arg(s, ChaCha20Rng::from_entropy()))).into_iter().collect();
+        // replaced by the ones the server picks
+        map = Map::default();
+        mode = Mode::Ffa;
     }
 
     let (mut rl, thread) = raylib::init()
@@ -59,12 +165,17 @@ fn main() -> std::io::Result<()> {
     let mut render = Renderer::new(&mut rl, &thread);
 
     // Most of these values doesn't matter. Its just for the compiler. They are initialized in ClientState::Waiting
-    let mut game_state: GameState = GameState::new(0, ChaCha20Rng::from_seed([0; 32]));
+    let mut game_state: GameState = GameState::new(0, ChaCha20Rng::from_seed([0; 32]), load_ruleset(), map, mode);
     let mut seq_state = SeqState::new();
     let mut frame_counter: i32 = 0;
-    let mut net = NetState::new();
+    let mut net = NetState::new(game_state.players(), game_state.p_id);
     let mut mouse_state: MouseState = MouseState::None;
     let mut game_ps = TimeWindowAvg::new();
+    let mut clock = SimClock::new(frame_rate);
+    // time and screen position of the last click, for double clicks
+    let mut last_click = None;
+    let mut templates = load_templates(&game_state.map.id);
+    let mut routes = RouteCache::default();
 
     let socket = UdpSocket::bind("0.0.0.0:0")?;
     socket.set_nonblocking(true)?;
@@ -83,18 +194,21 @@ fn main() -> std::io::Result<()> {
         if let Some(server) = m_server {
             let (m_start_with_seed, new_state) = handle_handshake(state, &socket, &server, &mut seq_state, &mut game_state.p_id);
             state = new_state;
-            if let Some(rng_seed) = m_start_with_seed {
+            if let Some((rng_seed, rules, map, mode)) = m_start_with_seed {
                 frame_counter = 0;
-                net = NetState::new();
+                clock = SimClock::new(frame_rate);
+                net = NetState::new(map.players(), game_state.p_id);
                 mouse_state = MouseState::None;
-                game_state = GameState::new(game_state.p_id, ChaCha20Rng::from_seed(rng_seed));
+                agents.iter_mut().for_each(|(a_id, _)| *a_id = game_state.p_id);
+                game_state = GameState::new(game_state.p_id, ChaCha20Rng::from_seed(rng_seed), rules, map, mode);
+                templates = load_templates(&game_state.map.id);
             }
         }
     
         state = match state {
             ClientState::Started => {
                 run_game(&mut game_state, &mut screen_changed, &mut zoom, &mut borderless,
-                    &mut rl, &mut mouse_state, &mut net, &mut frame_counter, &socket, &m_server, &mut seq_state, frame_rate, &mut game_ps)
+                    &mut rl, &mut mouse_state, &mut net, &mut frame_counter, &socket, &m_server, &mut seq_state, frame_rate, &mut game_ps, &mut agents, &mut templates, &mut clock, &mut last_click)
             },
             ClientState::Ended(end_state) => {
                 if rl.is_key_pressed(KeyboardKey::KEY_SPACE) {
@@ -107,8 +221,9 @@ fn main() -> std::io::Result<()> {
             _ => state
         };
 
-        render.render(&mut rl, &thread, frame_counter, &game_state, mouse_position, &mouse_state, &state, zoom,
-            &NetInfo { game_ps: &game_ps, waiting_avg: &net.waiting_avg, my_frame_delay: net.my_frame_delay }, screen_changed);
+        routes.update(&game_state, &mouse_state, Vector2::new(mouse_position.x.round(), mouse_position.y.round()));
+        render.render(&mut rl, &thread, frame_counter, &game_state, &clock, &routes, &templates, mouse_position, &mouse_state, &state, zoom,
+            &NetInfo { game_ps: &game_ps, waiting_avg: &net.waiting_avg, my_frame_delay: net.my_frame_delay, pending: net.pending() }, screen_changed);
     }
     if let Some(server) = m_server {
         socket_send(&socket, &server, &ClientPkt::Disconnect).unwrap();
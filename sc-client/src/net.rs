@@ -1,12 +1,14 @@
 use std::{net::{SocketAddr, UdpSocket}, time::Instant};
 
-use sc_types::{ClientPkt, GameCommand, SeqState, ServerEnum};
+use sc_types::{ClientPkt, GameCommand, Mode, SeqState, ServerEnum};
+use sc_types::map::Map;
+use sc_types::ruleset::Ruleset;
 
 use crate::{socket_recv, socket_send, ClientState, FrameMap, WindowAvg};
 
 pub fn handle_handshake(state: ClientState, socket: &UdpSocket, server: &SocketAddr, seq_state: &mut SeqState, p_id: &mut usize)
     // startGame with this seed
-    -> (Option<[u8; 32]>, ClientState) {
+    -> (Option<([u8; 32], Ruleset, Map, Mode)>, ClientState) {
     match state {
         ClientState::SendHello => {
             socket_send(&socket, server, &ClientPkt::Hello { seq: seq_state.send_seq, sent_time: 0.0 }).unwrap();
@@ -30,8 +32,15 @@ pub fn handle_handshake(state: ClientState, socket: &UdpSocket, server: &SocketA
             let resp = socket_recv(&socket, server, seq_state);
             match resp {
                 None => (None, ClientState::Waiting),
-                Some(ServerEnum::Start { rng_seed }) => {
-                    (Some(rng_seed), ClientState::Started)
+                Some(ServerEnum::Start { rng_seed, ruleset, ruleset_hash, map_id, map_hash, mode }) => {
+                    if ruleset.hash() != ruleset_hash {
+                        panic!("Ruleset hash mismatch")
+                    }
+                    let map = Map::load(&map_id).unwrap_or_else(|e| panic!("Unable to load map {}", e));
+                    if map.hash != map_hash {
+                        panic!("Map hash mismatch")
+                    }
+                    (Some((rng_seed, ruleset, map, mode)), ClientState::Started)
                 },
                 Some(_) => {
                     panic!("Expected Start")
@@ -45,17 +54,24 @@ pub fn handle_handshake(state: ClientState, socket: &UdpSocket, server: &SocketA
 pub enum NetProcessResult {
     WouldBlock,
     PeerDisconnect,
-    Success(Vec<GameCommand>, Vec<GameCommand>)
+    // what we sent and what every player sent, indexed by player id. Our own entry is empty.
+    Success(Vec<GameCommand>, Vec<Vec<GameCommand>>)
 }
 
 pub static MAX_PKT_QUEUE: usize = 40;
 pub struct NetState {
+    pub p_id: usize,
     pub next_send_frame: i32,
     pub unsent_pkt: Vec<GameCommand>,
+    // sandbox only, commands of the agents playing the other players that go out with our next packet
+    pub agent_unsent: Vec<Vec<GameCommand>>,
     pub unacked_pkts: FrameMap<Vec<GameCommand>>,
-    pub future_pkts: FrameMap<Vec<GameCommand>>, //rename recvd_pkts
+    // per player arrays are indexed by player id, our own entry is unused
+    pub future_pkts: Vec<FrameMap<Vec<GameCommand>>>, //rename recvd_pkts
     pub sent_pkts: FrameMap<Vec<GameCommand>>,
-    pub last_rcvd_pkt: i32,
+    pub last_rcvd_pkt: Vec<i32>,
+    // the last of our frames each player has acked
+    pub acked_by: Vec<i32>,
     pub my_frame_delay: u8,
     pub m_new_frame_delay: Option<u8>,
     pub waiting: Instant,
@@ -63,21 +79,24 @@ pub struct NetState {
 }
 
 impl NetState {
-    pub fn new() -> NetState {
+    pub fn new(players: usize, p_id: usize) -> NetState {
         let default_fram_delay = 1;
-        let mut future_pkts = FrameMap::new();
+        let mut future_pkts: Vec<FrameMap<Vec<GameCommand>>> = (0..players).map(|_| FrameMap::new()).collect();
         let mut sent_pkts = FrameMap::new();
         for i in 0..default_fram_delay {
-            future_pkts.push(i as i32, vec![]);
+            future_pkts.iter_mut().for_each(|f| f.push(i as i32, vec![]));
             sent_pkts.push(i as i32, vec![]);                            
         }
         NetState {
+            p_id,
             next_send_frame: 0,
             unsent_pkt: vec![],
+            agent_unsent: vec![vec![]; players],
             unacked_pkts: FrameMap::new(),
             future_pkts,
             sent_pkts,
-            last_rcvd_pkt: -1,
+            last_rcvd_pkt: vec![-1; players],
+            acked_by: vec![-1; players],
             my_frame_delay: default_fram_delay,
             m_new_frame_delay: None,
             waiting: Instant::now(),
@@ -85,24 +104,45 @@ impl NetState {
         }
     }
 
+    fn others(self: &Self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.future_pkts.len()).filter(move |p| *p != self.p_id)
+    }
+
+    // Our commands that haven't been stepped yet, with the frame they are due on
+    pub fn pending(self: &Self) -> Vec<(i32, &GameCommand)> {
+        let unsent_frame = self.next_send_frame + self.my_frame_delay as i32;
+        self.sent_pkts.iter().flat_map(|(f, cs)| cs.iter().map(move |c| (*f, c)))
+            .chain(self.unsent_pkt.iter().map(|c| (unsent_frame, c)))
+            .collect()
+    }
+
     pub fn queue_command(self: &mut Self, command: GameCommand) {
         if self.unsent_pkt.len() < MAX_PKT_QUEUE {
             self.unsent_pkt.push(command);
         }
     }
 
+    pub fn queue_agent_command(self: &mut Self, a_id: usize, command: GameCommand) {
+        if self.agent_unsent[a_id].len() < MAX_PKT_QUEUE {
+            self.agent_unsent[a_id].push(command);
+        }
+    }
+
     pub fn process(self: &mut Self, frame_counter: i32, socket: &UdpSocket, m_server: &Option<SocketAddr>, seq_state: &mut SeqState, frame_rate: u32) 
         -> NetProcessResult {
         if let Some(server) = m_server {
             let resp = socket_recv(&socket, server, seq_state);
             match resp {
                 None => {}
-                Some(ServerEnum::UpdateOtherTarget { updates, frame, frame_ack, frame_delay: _ }) => {
+                Some(ServerEnum::UpdateOtherTarget { player_id, updates, frame, frame_ack, frame_delay: _ }) => {
                     self.waiting_avg.sample(self.waiting.elapsed().as_secs_f64());
                     self.waiting = Instant::now();
-                    self.future_pkts.merge(&updates.clone());
-                    self.unacked_pkts.retain(|ps| ps.0 > frame_ack);
-                    self.last_rcvd_pkt = frame;
+                    self.future_pkts[player_id].merge(&updates.clone());
+                    self.acked_by[player_id] = frame_ack;
+                    // keep resending until everyone has it
+                    let acked = self.others().map(|p| self.acked_by[p]).min().unwrap_or(frame_ack);
+                    self.unacked_pkts.retain(|ps| ps.0 > acked);
+                    self.last_rcvd_pkt[player_id] = frame;
                 },
                 Some(ServerEnum::PeerDisconnect) => {
                     return NetProcessResult::PeerDisconnect;
@@ -140,28 +180,44 @@ impl NetState {
                         ack: seq_state.send_ack,
                         updates: self.unacked_pkts.cloned_vecdeque(),
                         frame: frame_counter + self.my_frame_delay as i32,
-                        frame_ack: self.last_rcvd_pkt,
+                        // the server relays this to everyone, so only ack what we have from all of them
+                        frame_ack: self.others().map(|p| self.last_rcvd_pkt[p]).min().unwrap_or(-1),
                         frame_delay: self.my_frame_delay
                     }).unwrap();
                     seq_state.send();
                 }
                 self.sent_pkts.push(frame_counter + self.my_frame_delay as i32, self.unsent_pkt.clone());
                 self.unsent_pkt = vec![];
+                // sandbox agents' commands are due on the same frame as ours, so they react no faster than we can
+                if m_server.is_none() {
+                    let others: Vec<usize> = self.others().collect();
+                    for p in others {
+                        let commands = std::mem::take(&mut self.agent_unsent[p]);
+                        self.future_pkts[p].push(frame_counter + self.my_frame_delay as i32, commands);
+                    }
+                }
             }
             self.next_send_frame += 1;
         }
 
-        let other_pkt_exists = self.future_pkts.iter().any(|ps| ps.0 == frame_counter);
-        if m_server.is_none() && !other_pkt_exists {
-            self.future_pkts.push(frame_counter, vec![]);
+        let others: Vec<usize> = self.others().collect();
+        if m_server.is_none() {
+            for p in others.iter() {
+                if !self.future_pkts[*p].iter().any(|ps| ps.0 == frame_counter) {
+                    self.future_pkts[*p].push(frame_counter, vec![]);
+                }
+            }
         }
+        let other_pkts_exist = others.iter().all(|p| self.future_pkts[*p].iter().any(|ps| ps.0 == frame_counter));
         // next_send_frame > frame_counter should be equivalent to sent_pkts.any(.0 == frame_counter)
-        let result = if (self.next_send_frame > frame_counter) && (other_pkt_exists || m_server.is_none()) {
-            let recvd_pkt = self.future_pkts.iter().find(|ps| ps.0 == frame_counter).unwrap().1.clone();
+        let result = if (self.next_send_frame > frame_counter) && other_pkts_exist {
+            let recvd_pkts = (0..self.future_pkts.len()).map(|p| {
+                self.future_pkts[p].iter().find(|ps| ps.0 == frame_counter).map(|ps| ps.1.clone()).unwrap_or_default()
+            }).collect();
             let sent_pkt = self.sent_pkts.iter().find(|ps| ps.0 == frame_counter).unwrap().1.clone();
-            self.future_pkts.retain(|ps| ps.0 > frame_counter);
+            self.future_pkts.iter_mut().for_each(|f| f.retain(|ps| ps.0 > frame_counter));
             self.sent_pkts.retain(|ps| ps.0 > frame_counter);
-            NetProcessResult::Success(sent_pkt, recvd_pkt)
+            NetProcessResult::Success(sent_pkt, recvd_pkts)
         } else {
             NetProcessResult::WouldBlock
         };
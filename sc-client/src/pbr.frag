@@ -51,8 +51,9 @@ uniform vec3 cubePos[20];
 uniform vec3 cubeSize;
 uniform int numCubes;
 
-uniform vec3 gcubePos[2];
-uniform float gcubeSize[2];
+// one per ship, unused entries repeat ship 0
+uniform vec3 gcubePos[4];
+uniform float gcubeSize[4];
 
 uniform vec3 bountyPos[20];
 uniform float bountyR;
@@ -86,8 +87,9 @@ float map(in vec3 pos) {
     for (int i = 0; i < numCubes; i++) {
         result = min(result, sdBox(pos - cubePos[i], cubeSize/2));
     }
-    result = min(result, sdBox(pos - gcubePos[0], cubeSize*gcubeSize[0]/2));
-    result = min(result, sdBox(pos - gcubePos[1], cubeSize*gcubeSize[1]/2));
+    for (int i = 0; i < 4; i++) {
+        result = min(result, sdBox(pos - gcubePos[i], cubeSize*gcubeSize[i]/2));
+    }
     for (int i = 0; i < numBounties; i++) {
         result = min(result, sdSphere(pos - bountyPos[i], bountyR));
     }
@@ -2,9 +2,14 @@ use std::{collections::{HashMap, VecDeque}, f32::consts::PI};
 use raylib::prelude::*;
 use sc_types::*;
 use sc_types::constants::*;
+use sc_types::map::{ZoneKind, MAX_PLAYERS};
+use sc_types::ruleset::Ruleset;
+use sc_types::shapes::Rect;
+use sc_types::sim::{path_lumber_cost, retarget, retarget_lumber_cost, rounded, validate_command};
 use serde_json::Value;
 
-use crate::{path_lumber_cost, rounded, scale_color, vec2, vec3, ClientState, Interception, MouseState, NetInfo};
+use crate::{moved_point, scale_color, PathKind, shop_open, vec2, vec3, ClientState, RouteCache, SimClock, Interception, MouseState, NetInfo};
+use crate::templates::Templates;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -82,6 +87,15 @@ fn draw_4border(img: &mut Image, c: Color, thickness: i32) {
     }
 }
 
+fn bounty_color_key(type_: BountyEnum) -> &'static str {
+    match type_ {
+        BountyEnum::Blink => "blink",
+        BountyEnum::Fuel => "fuel",
+        BountyEnum::Gold => "gold",
+        BountyEnum::Lumber => "lumber",
+    }
+}
+
 struct Constants(Value);
 
 impl Constants {
@@ -160,13 +174,24 @@ pub struct Renderer {
     xtr_sky: Texture2D,
     tile: Image,
     xtr_tile: Texture2D,
-    p0int_tile: Image,
-    xtr_p0int_tile: Texture2D,
-    p1int_tile: Image,
-    xtr_p1int_tile: Texture2D,
+    // indexed by player id
+    int_tiles: Vec<Image>,
+    xtr_int_tiles: Vec<Texture2D>,
     locs: ShaderLocs,
 }
 
+fn load_int_tiles(rl: &mut RaylibHandle, thread: &RaylibThread, cs: &Constants) -> (Vec<Image>, Vec<Texture2D>) {
+    let mut int_tiles = vec![];
+    let mut xtr_int_tiles = vec![];
+    for p_id in 0..MAX_PLAYERS {
+        let mut int_tile = Image::gen_image_color(256, 256, cs.get_p_color("message_color", p_id));
+        draw_4border(&mut int_tile, cs.get_p_color("intercept_border_color", p_id), cs.get_i32("intercept_border_thickness"));
+        xtr_int_tiles.push(rl.load_texture_from_image(&thread, &mut int_tile).unwrap());
+        int_tiles.push(int_tile);
+    }
+    (int_tiles, xtr_int_tiles)
+}
+
 impl Renderer {
     #[cfg(debug_assertions)]
     fn load_constants() -> Constants {
@@ -187,13 +212,7 @@ impl Renderer {
         self.xtr_tile = rl.load_texture_from_image(&thread, &mut self.tile).unwrap();
         self.floor.materials_mut()[0].set_material_texture(MaterialMapIndex::MATERIAL_MAP_ALBEDO, &self.xtr_tile);
 
-        self.p0int_tile = Image::gen_image_color(256, 256, self.cs.get_p_color("message_color", 0));
-        draw_4border(&mut self.p0int_tile, self.cs.get_p_color("intercept_border_color", 0), self.cs.get_i32("intercept_border_thickness"));
-        self.xtr_p0int_tile = rl.load_texture_from_image(&thread, &mut self.p0int_tile).unwrap();
-
-        self.p1int_tile = Image::gen_image_color(256, 256, self.cs.get_p_color("message_color", 1));
-        draw_4border(&mut self.p1int_tile, self.cs.get_p_color("intercept_border_color", 1), self.cs.get_i32("intercept_border_thickness"));
-        self.xtr_p1int_tile = rl.load_texture_from_image(&thread, &mut self.p1int_tile).unwrap();
+        (self.int_tiles, self.xtr_int_tiles) = load_int_tiles(rl, thread, &self.cs);
     }
 
     #[cfg(not(debug_assertions))]
@@ -255,13 +274,7 @@ impl Renderer {
         let xtr_tile = rl.load_texture_from_image(&thread, &mut tile).unwrap();
         floor.materials_mut()[0].set_material_texture(MaterialMapIndex::MATERIAL_MAP_ALBEDO, &xtr_tile);
 
-        let mut p0int_tile = Image::gen_image_color(256, 256, cs.get_p_color("message_color", 0));
-        draw_4border(&mut p0int_tile, cs.get_p_color("intercept_border_color", 0), cs.get_i32("intercept_border_thickness"));
-        let xtr_p0int_tile = rl.load_texture_from_image(&thread, &mut p0int_tile).unwrap();
-
-        let mut p1int_tile = Image::gen_image_color(256, 256, cs.get_p_color("message_color", 1));
-        draw_4border(&mut p1int_tile, cs.get_p_color("intercept_border_color", 1), cs.get_i32("intercept_border_thickness"));
-        let xtr_p1int_tile = rl.load_texture_from_image(&thread, &mut p1int_tile).unwrap();
+        let (int_tiles, xtr_int_tiles) = load_int_tiles(rl, thread, &cs);
         
         shader.set_shader_value(shader.get_shader_location("useTexNormal"), 0);
         shader.set_shader_value(shader.get_shader_location("useTexMRA"), 0);
@@ -280,10 +293,8 @@ impl Renderer {
             xtr_sky,
             tile,
             xtr_tile,
-            p0int_tile,
-            xtr_p0int_tile,
-            p1int_tile,
-            xtr_p1int_tile,
+            int_tiles,
+            xtr_int_tiles,
             locs: ShaderLocs {
                 use_tex_albedo: shader.get_shader_location("useTexAlbedo"),
                 use_tex_emissive: shader.get_shader_location("useTexEmissive"),
@@ -334,6 +345,50 @@ impl Renderer {
     }
 
     // Returns Vec2 in clip space (-1,-1) -> (1,1)
+    // Screen space rows of the shop panel, also used by game.rs to find what was clicked
+    pub fn shop_layout(screen_width: f64, screen_height: f64) -> Vec<(ShopItem, Rect<f32>)> {
+        let sh = screen_height as f32;
+        let text_size = (sh/50.0).max(10.0);
+        let width = sh/2.5;
+        let row_h = text_size * 1.5;
+        let x = screen_width as f32 - width - sh/50.0;
+        // leave room for the title
+        let y = sh/50.0 + row_h;
+        ShopItem::all().into_iter().enumerate().map(|(i, item)| (item, Rect { x, y: y + i as f32 * row_h, w: width, h: row_h })).collect()
+    }
+
+    fn render_shop(self: &Self, _d: &mut RaylibDrawHandle, game_state: &GameState, raw_mouse_position: Vector2, screen_width: f64, screen_height: f64) {
+        let p_id = game_state.p_id;
+        let layout = Renderer::shop_layout(screen_width, screen_height);
+        let sh = screen_height as f32;
+        let text_size = (sh/50.0).max(10.0);
+        let pad = sh/100.0;
+        let (first, last) = (layout[0].1, layout[layout.len() - 1].1);
+        _d.draw_rectangle_rounded(Rectangle { x: first.x - pad, y: first.y - first.h - pad, width: first.w + pad * 2.0, height: last.y + last.h - first.y + first.h + pad * 2.0 },
+            0.1, 10, Color::BLACK.alpha(0.5));
+        _d.draw_text(&format!("Shop (S) Gold: {}", game_state.gold[p_id].round()), first.x as i32, (first.y - first.h) as i32, text_size as i32, Color::WHITE);
+
+        for (item, r) in layout {
+            if r.contains_point(&raw_mouse_position) {
+                _d.draw_rectangle_rec(Rectangle { x: r.x, y: r.y, width: r.w, height: r.h }, self.cs.get_color("shop_hover"));
+            }
+            let owned = match item {
+                ShopItem::Upgrade(u) => if game_state.upgrades[p_id].contains(&u) { "owned".to_string() } else { String::new() },
+                ShopItem::Item(i) => format!("{}/{}", game_state.item_count(p_id, i), i.max_stock(&game_state.rules)),
+            };
+            let c = if validate_command(game_state, p_id, &item.buy_command()) {
+                Color::WHITE
+            } else if owned == "owned" {
+                self.cs.get_color("shop_owned")
+            } else {
+                self.cs.get_color("shop_unavailable")
+            };
+            let text_y = (r.y + (r.h - text_size)/2.0) as i32;
+            _d.draw_text(item.name(), (r.x + pad) as i32, text_y, text_size as i32, c);
+            _d.draw_text(&format!("{}g {}", item.cost(&game_state.rules).round(), owned), (r.x + r.w * 0.6) as i32, text_y, text_size as i32, c);
+        }
+    }
+
     pub fn screen2clip(raw_mouse_position: Vector2, screen_width: f64, screen_height: f64) -> Vector2 {
         let screen2clip_mat = Matrix::translate(-1.0, 1.0, 0.0) *
             Matrix::scale(2.0/screen_width as f32, -2.0/screen_height as f32, 1.0);
@@ -365,38 +420,48 @@ impl Renderer {
         _3d.draw_model(&self.plane, pos, 1.0, highlight_color);
     }
 
-    pub fn render_map(self: &mut Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, mouse_position: Vector3, interceptions: &Vec<Interception>, frame_counter: i32) {
+    pub fn render_map(self: &mut Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, mouse_position: Vector3, game_state: &GameState, frame_counter: i32) {
+        let (interceptions, map) = (game_state.visible_interceptions(game_state.p_id), &game_state.map);
         enum Tile {
             Intercept(Interception),
             Color(Color, Vector4, f32)
         }
         let mut tile_color = HashMap::new();
         for i in interceptions {
-            let alpha = ((frame_counter - i.start_frame) as f32/INTERCEPT_DELAY as f32).min(1.0);
-            let c = self.cs.get_color("tile_tint").color_normalize().lerp(self.cs.get_p_color("message_color", i.player_id).color_normalize(), alpha);
-            let e = self.cs.get_color("e_color").color_normalize().lerp(self.cs.get_p_color("message_emission", i.player_id).color_normalize(), alpha);
-            let end_ep = self.cs.get_f32(&format!("message_e_power{}", i.player_id));
-            let start_ep = self.cs.get_f32("e_power");
-            let ep = start_ep + (end_ep - start_ep)*alpha;
+            for t in i.tiles() {
+                tile_color.insert((t.x as i32, t.y as i32), Tile::Intercept(*i));
+            }
+        }
 
-            tile_color.insert((i.pos.x as i32, i.pos.y as i32), 
-                Tile::Intercept(*i));
+        for z in map.zones.iter() {
+            let key = match z.kind {
+                ZoneKind::River => "river",
+                ZoneKind::Nebula => "nebula",
+                ZoneKind::FastLane => "fast_lane",
+            };
+            for x in z.area.x..=(z.area.x + z.area.w) {
+                for y in z.area.y..=(z.area.y + z.area.h) {
+                    tile_color.entry((x, y)).or_insert(Tile::Color(self.cs.get_color(key),
+                        self.cs.get_color("e_color").color_normalize(), self.cs.get_f32("e_power")));
+                }
+            }
         }
 
-        for s in station(0) {
-            tile_color.insert((s.x as i32, s.y as i32), Tile::Color(self.cs.get_p_color("message_color", 0),
-                self.cs.get_p_color("message_emission", 0).color_normalize(),
-                self.cs.get_f32(&format!("message_e_power{}", 0))));
+        for p in 0..map.players() {
+            for s in map.station(p) {
+                tile_color.insert((s.x as i32, s.y as i32), Tile::Color(self.cs.get_p_color("message_color", p),
+                    self.cs.get_p_color("message_emission", p).color_normalize(),
+                    self.cs.get_f32(&format!("message_e_power{}", p))));
+            }
         }
 
-        for s in station(1) {
-            tile_color.insert((s.x as i32, s.y as i32), Tile::Color(self.cs.get_p_color("message_color", 1),
-                self.cs.get_p_color("message_emission", 1).color_normalize(),
-                self.cs.get_f32(&format!("message_e_power{}", 1))));
+        for b in map.blocked.iter() {
+            tile_color.insert((b.x as i32, b.y as i32), Tile::Color(self.cs.get_color("blocked"),
+                self.cs.get_color("e_color").color_normalize(), self.cs.get_f32("e_power")));
         }
 
         let rounded_mpos = rounded(vec2(mouse_position));
-        if PLAY_AREA.contains_point(&rounded_mpos) {
+        if map.area.contains_point(&rounded_mpos) {
             tile_color.entry((rounded_mpos.x as i32, rounded_mpos.y as i32)).and_modify(|e| 
                 match *e { 
                     Tile::Color(c, ec, ep) => *e = Tile::Color(scale_color(c, self.cs.get_f32("highlight_mult")), ec, ep),
@@ -411,8 +476,8 @@ impl Renderer {
         self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_color("e_color").color_normalize());
         self.shader.set_shader_value(self.locs.use_tex_albedo, 1);
         // PERF 1 plane 2 triangles
-        for x in -12..=12 {
-            for y in -12..=12 {
+        for x in map.area.x..=(map.area.x + map.area.w) {
+            for y in map.area.y..=(map.area.y + map.area.h) {
                 let mut c = Color::WHITE; //self.cs.get_color("tile_tint");
                 let mut reset_emissive = false;
                 let mut reset_xtr = false;
@@ -425,7 +490,7 @@ impl Renderer {
                             self.shader.set_shader_value(self.locs.use_tex_albedo, 0);
                         }
                         Tile::Intercept(i) => {
-                            let alpha = ((frame_counter - i.start_frame) as f32/INTERCEPT_DELAY as f32).min(1.0);
+                            let alpha = ((frame_counter - i.start_frame) as f32/i.delay as f32).min(1.0);
                             if alpha < 1.0 {
                                 c = Color::color_from_normalized(
                                     self.cs.get_color("tile_tint").color_normalize().lerp(
@@ -440,18 +505,16 @@ impl Renderer {
                             } else {
                                 self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", i.player_id).color_normalize());
                                 self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", i.player_id)));
-                                if i.player_id == 0 {
-                                    self.floor.materials_mut()[0].set_material_texture(MaterialMapIndex::MATERIAL_MAP_ALBEDO, &self.xtr_p0int_tile);
-                                } else {
-                                    self.floor.materials_mut()[0].set_material_texture(MaterialMapIndex::MATERIAL_MAP_ALBEDO, &self.xtr_p1int_tile);
-                                }
+                                self.floor.materials_mut()[0].set_material_texture(MaterialMapIndex::MATERIAL_MAP_ALBEDO, &self.xtr_int_tiles[i.player_id]);
                                 reset_xtr = true;
                             }
                         }
                     }
                     reset_emissive = true;
                 }
-                self.floor.set_transform(&(Matrix::translate(x as f32, y as f32, 0.0) * Matrix::rotate_x(PI/2.0)));
+                // blocked tiles are raised
+                let z = if map.blocked.contains(&Vector2::new(x as f32, y as f32)) { self.cs.get_f32("blocked_height") } else { 0.0 };
+                self.floor.set_transform(&(Matrix::translate(x as f32, y as f32, z) * Matrix::rotate_x(PI/2.0)));
                 _3d.draw_model(&self.floor, Vector3::zero(), 1.0, c);
 
                 if reset_emissive {
@@ -469,31 +532,41 @@ impl Renderer {
         self.shader.set_shader_value(self.locs.use_tex_emissive, 0);
 
         self.plane.set_transform(&(Matrix::rotate_x(PI)));
-        for x in -12..=12 {
-            _3d.draw_model(&self.plane, Vector3::new(x as f32, -12.5, -0.5), 1.0, self.cs.get_color("cliff"));
+        for x in map.area.x..=(map.area.x + map.area.w) {
+            _3d.draw_model(&self.plane, Vector3::new(x as f32, map.area.y as f32 - 0.5, -0.5), 1.0, self.cs.get_color("cliff"));
         }
         self.plane.set_transform(&(Matrix::rotate_z(PI/2.0)));
-        for y in -12..=12 {
-            _3d.draw_model(&self.plane, Vector3::new(-12.5, y as f32, -0.5), 1.0, self.cs.get_color("cliff"));
+        for y in map.area.y..=(map.area.y + map.area.h) {
+            _3d.draw_model(&self.plane, Vector3::new(map.area.x as f32 - 0.5, y as f32, -0.5), 1.0, self.cs.get_color("cliff"));
+        }
+
+        // only the sides facing the camera, like the cliffs
+        let blocked_height = self.cs.get_f32("blocked_height");
+        for b in map.blocked.iter() {
+            self.plane.set_transform(&(Matrix::scale(1.0, 1.0, blocked_height) * Matrix::rotate_x(PI)));
+            _3d.draw_model(&self.plane, Vector3::new(b.x, b.y - 0.5, blocked_height/2.0), 1.0, self.cs.get_color("blocked_wall"));
+            self.plane.set_transform(&(Matrix::scale(1.0, 1.0, blocked_height) * Matrix::rotate_z(PI/2.0)));
+            _3d.draw_model(&self.plane, Vector3::new(b.x - 0.5, b.y, blocked_height/2.0), 1.0, self.cs.get_color("blocked_wall"));
         }
         self.shader.set_shader_value(self.locs.emissive_power, 0f32);
     }
     
-    fn render_ships(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState, cube_z_offset: f32, cube_side_len: f32, cube: &mut Model, p_id: usize) {
-        self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32("message_e_power0"));
-        self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", 0).color_normalize());
-
-        let alpha = |i| { (MSG_COOLDOWN - game_state.spawn_cooldown[i]) as f32/MSG_COOLDOWN as f32 };
-        self.shader.set_shader_value_v(self.locs.gcube_pos, &[vec3(*ship(0), cube_z_offset), vec3(*ship(1), cube_z_offset)]);
-        self.shader.set_shader_value_v(self.locs.gcube_size, &[alpha(0) * cube_side_len, alpha(1) * cube_side_len]);
-
-        cube.set_transform(&Matrix::scale(alpha(0) * cube_side_len, alpha(0) * cube_side_len, alpha(0) * cube_side_len));
-        _3d.draw_model(&cube, vec3(*ship(0), cube_z_offset), 1.0, self.cs.get_p_color("message_color", 0));
-
-        self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32("message_e_power1"));
-        self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", 1).color_normalize());
-        cube.set_transform(&Matrix::scale(alpha(1) * cube_side_len, alpha(1) * cube_side_len, alpha(1) * cube_side_len));
-        _3d.draw_model(&cube, vec3(*ship(1), cube_z_offset), 1.0, self.cs.get_p_color("message_color", 1));
+    fn render_ships(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState, unit_pos: &HashMap<usize, Vector2>, cube_z_offset: f32, cube_side_len: f32, cube: &mut Model, p_id: usize) {
+        let msg_cooldown = game_state.rules.msg_cooldown;
+        let alpha = |i| { (msg_cooldown - game_state.spawn_cooldown[i]) as f32/msg_cooldown as f32 };
+        // the shader always takes MAX_PLAYERS ships, pad with copies of ship 0
+        let ships: Vec<usize> = (0..MAX_PLAYERS).map(|p| if p < game_state.players() { p } else { 0 }).collect();
+        let gcube_pos: Vec<Vector3> = ships.iter().map(|p| vec3(*game_state.map.ship(*p), cube_z_offset)).collect();
+        let gcube_size: Vec<f32> = ships.iter().map(|p| alpha(*p) * cube_side_len).collect();
+        self.shader.set_shader_value_v(self.locs.gcube_pos, gcube_pos.as_slice());
+        self.shader.set_shader_value_v(self.locs.gcube_size, gcube_size.as_slice());
+
+        for p in 0..game_state.players() {
+            self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", p)));
+            self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", p).color_normalize());
+            cube.set_transform(&Matrix::scale(alpha(p) * cube_side_len, alpha(p) * cube_side_len, alpha(p) * cube_side_len));
+            _3d.draw_model(&cube, vec3(*game_state.map.ship(p), cube_z_offset), 1.0, self.cs.get_p_color("message_color", p));
+        }
     
         self.shader.set_shader_value(self.locs.emissive_power, 0f32);
         cube.set_transform(&Matrix::identity());
@@ -501,9 +574,12 @@ impl Renderer {
         self.lights[0].enabled = 0;
         update_light(&mut self.shader, &self.lights[0]);
 
-        self.draw_cube_outline(_3d, vec3(*ship(p_id), 0.0), cube_side_len, cube_z_offset, self.cs.get_color("selection"), self.cs.get_f32("selection_thickness"));
-        for u in game_state.selection.iter().filter(|s| if let Selection::Unit(_) = s { true } else { false }).map(|s| if let Selection::Unit(u) = s { game_state.my_units[*u].clone() } else { panic!("impossible") }) {
-            self.draw_cube_outline(_3d, vec3(u.pos, 0.0), cube_side_len, cube_z_offset, self.cs.get_color("selection"), self.cs.get_f32("selection_thickness"));
+        self.draw_cube_outline(_3d, vec3(*game_state.map.ship(p_id), 0.0), cube_side_len, cube_z_offset, self.cs.get_color("selection"), self.cs.get_f32("selection_thickness"));
+        for u in game_state.units(p_id).iter().filter(|u| game_state.selection.contains(&Selection::Unit(u.id))) {
+            self.draw_cube_outline(_3d, vec3(unit_pos[&u.id], 0.0), cube_side_len, cube_z_offset, self.cs.get_color("selection"), self.cs.get_f32("selection_thickness"));
+        }
+        for u in game_state.all_units().filter(|u| u.shield > 0) {
+            self.draw_cube_outline(_3d, vec3(unit_pos[&u.id], 0.0), cube_side_len * self.cs.get_f32("shield_scale"), cube_z_offset, self.cs.get_color("shield"), self.cs.get_f32("selection_thickness"));
         }
 
         self.lights[0].enabled = 1;
@@ -511,12 +587,13 @@ impl Renderer {
     }
 
     // Returns positions of packed bounties to be sent to frag shader for shadows
-    fn render_messages(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState, cube_z_offset: f32, cube: &mut Model, cube_side_len: f32, p_id: usize, frame_counter: i32) -> Vec<Vector3> {
-        fn draw_packed_bounties(u: &Unit, r: &Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, frame_counter: i32) -> Vec<Vector3> {
+    // unit_pos is where to draw each unit, see SimClock::unit_positions
+    fn render_messages(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState, unit_pos: &HashMap<usize, Vector2>, cube_z_offset: f32, cube: &mut Model, cube_side_len: f32, p_id: usize, frame_counter: i32) -> Vec<Vector3> {
+        fn draw_packed_bounties(u: &Unit, pos: Vector2, r: &Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, rules: &Ruleset, frame_counter: i32) -> Vec<Vector3> {
             let mut i = 0;
             let mut out = vec![];
             // TODO function for calculating number of bounties num_bounties(BountyEnum, amount: i32) -> i32
-            let tot_carried_bounties = u.carrying_bounty.iter().fold(0, |acc, (b, n)| acc + if *b == BountyEnum::Blink { 1 } else { *n/b.amount() });
+            let tot_carried_bounties = u.carrying_bounty.iter().fold(0, |acc, (b, n)| acc + if *b == BountyEnum::Blink { 1 } else { *n/b.amount(rules) });
             for (b, n) in u.carrying_bounty.iter() {
                 let k = match b {
                     BountyEnum::Blink => "blink",
@@ -533,7 +610,7 @@ impl Renderer {
                     if phi >= 2.0 * PI {
                         break;
                     }
-                    let p = Vector3::new(u.pos.x + pbr * (frame_counter as f32 * pbs + phi).sin(), u.pos.y + pbr * (frame_counter as f32 * pbs + phi).cos(), pbz);
+                    let p = Vector3::new(pos.x + pbr * (frame_counter as f32 * pbs + phi).sin(), pos.y + pbr * (frame_counter as f32 * pbs + phi).cos(), pbz);
                     _3d.draw_model(&r.sphere, p,
                         r.cs.get_f32("bounty_r"), r.cs.get_color(k));
                     out.push(p);
@@ -541,7 +618,7 @@ impl Renderer {
                     if *b == BountyEnum::Blink {
                         b_amount = 0;
                     } else {
-                        b_amount -= b.amount();
+                        b_amount -= b.amount(rules);
                     }
                 }
             }
@@ -549,9 +626,11 @@ impl Renderer {
         }
 
         let mut packed_b_pos = vec![];
-        let other_id = (p_id + 1) % 2;
 
-        let cubes = game_state.my_units.iter().chain(game_state.other_units.iter()).map(|u| vec3(u.pos, cube_z_offset)).collect::<Vec<Vector3>>();
+        let pos = |u: &Unit| unit_pos[&u.id];
+        let cubes = game_state.all_units().map(|u| vec3(pos(u), cube_z_offset)).collect::<Vec<Vector3>>();
+        let my_units = game_state.units(p_id);
+        let other_units: Vec<&Unit> = (0..game_state.players()).filter(|p| *p != p_id).flat_map(|p| game_state.units(p).iter()).collect();
         if cubes.len() <= 20 {
             self.shader.set_shader_value_v(self.locs.cube_pos, cubes.as_slice());
             self.shader.set_shader_value(self.locs.num_cubes, cubes.len() as i32);
@@ -560,26 +639,34 @@ impl Renderer {
         }
         self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", p_id)));
         self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", p_id).color_normalize());
-        for u in game_state.my_units.iter() {
-            cube.set_transform(&(Matrix::translate(u.pos.x, u.pos.y, cube_z_offset) * Matrix::scale(cube_side_len, cube_side_len, cube_side_len)));
-            _3d.draw_model(&cube, Vector3::zero(), 1.0, self.cs.get_p_color("message_color", u.player_id));
+        // only we and our team know which ones are decoys
+        let color = |r: &Renderer, u: &Unit| if u.decoy && !game_state.enemies(u.player_id, p_id) {
+            scale_color(r.cs.get_p_color("message_color", u.player_id), r.cs.get_f32("decoy_color_mult"))
+        } else {
+            r.cs.get_p_color("message_color", u.player_id)
+        };
+        for u in my_units.iter() {
+            let p = pos(u);
+            cube.set_transform(&(Matrix::translate(p.x, p.y, cube_z_offset) * Matrix::scale(cube_side_len, cube_side_len, cube_side_len)));
+            _3d.draw_model(&cube, Vector3::zero(), 1.0, color(self, u));
         }
 
         self.shader.set_shader_value(self.locs.emissive_power, 0.0);
-        for u in game_state.my_units.iter() {
-            packed_b_pos.append(&mut draw_packed_bounties(u, self, _3d, frame_counter));
+        for u in my_units.iter() {
+            packed_b_pos.append(&mut draw_packed_bounties(u, pos(u), self, _3d, &game_state.rules, frame_counter));
         }
 
-        self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", other_id)));
-        self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", other_id).color_normalize());
-        for u in game_state.other_units.iter() {
-            cube.set_transform(&(Matrix::translate(u.pos.x, u.pos.y, cube_z_offset) * Matrix::scale(cube_side_len, cube_side_len, cube_side_len)));
-            _3d.draw_model(&cube, Vector3::zero(), 1.0, self.cs.get_p_color("message_color", u.player_id));
+        for u in other_units.iter().copied() {
+            let p = pos(u);
+            self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", u.player_id)));
+            self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", u.player_id).color_normalize());
+            cube.set_transform(&(Matrix::translate(p.x, p.y, cube_z_offset) * Matrix::scale(cube_side_len, cube_side_len, cube_side_len)));
+            _3d.draw_model(&cube, Vector3::zero(), 1.0, color(self, u));
         }
 
         self.shader.set_shader_value(self.locs.emissive_power, 0.0);
-        for u in game_state.other_units.iter() {
-            packed_b_pos.append(&mut draw_packed_bounties(u, self, _3d, frame_counter));
+        for u in other_units.iter().copied() {
+            packed_b_pos.append(&mut draw_packed_bounties(u, pos(u), self, _3d, &game_state.rules, frame_counter));
         }
 
         self.shader.set_shader_value(self.locs.emissive_power, 0f32);
@@ -597,21 +684,70 @@ impl Renderer {
             self.shader.set_shader_value(self.locs.num_bounties, 0);
         }
         for b in bounties.iter() {
-            let k = match b.type_ {
-                BountyEnum::Blink => "blink",
-                BountyEnum::Fuel => "fuel",
-                BountyEnum::Gold => "gold",
-                BountyEnum::Lumber => "lumber",
-            };
-            _3d.draw_model(&self.sphere, vec3(b.pos, bounty_z), self.cs.get_f32("bounty_r"), self.cs.get_color(k));
+            _3d.draw_model(&self.sphere, vec3(b.pos, bounty_z), self.cs.get_f32("bounty_r"), self.cs.get_color(bounty_color_key(b.type_)));
+        }
+    }
+
+    // The hook and the line back to its ship. A grabbed bounty rides on the hook.
+    fn render_grapples(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState) {
+        let z = self.cs.get_f32("bounty_z");
+        for g in game_state.grapples.iter() {
+            let c = self.cs.get_p_color("message_color", g.player_id);
+            _3d.draw_line_3D(vec3(*game_state.map.ship(g.player_id), z), vec3(g.pos, z), c);
+            _3d.draw_model(&self.sphere, vec3(g.pos, z), self.cs.get_f32("grapple_r"), c);
+            if let Some(b) = g.bounty {
+                _3d.draw_model(&self.sphere, vec3(g.pos, z + self.cs.get_f32("grapple_r")), self.cs.get_f32("bounty_r"), self.cs.get_color(bounty_color_key(b.type_)));
+            }
         }
     }
 
-    pub fn render_path(self: &mut Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, path: &VecDeque<Vector2>, p_id: usize) {
+    // Stand-ins for our commands until they are stepped, so input feels immediate despite the frame delay.
+    // Commands that are no longer legal are dropped here since the sim will drop them too.
+    fn render_pending(self: &mut Self, _3d: &mut RaylibMode3D<RaylibDrawHandle>, game_state: &GameState, net_info: &NetInfo, frame_counter: i32) {
+        let p_id = game_state.p_id;
+        let mult = self.cs.get_f32("pending_color_mult");
+        let c = scale_color(self.cs.get_p_color("message_color", p_id), mult);
+        let bring_front = rvec3(-0.01, -0.01, 0.01);
+        for (frame, command) in net_info.pending.iter() {
+            if !validate_command(game_state, p_id, command) {
+                continue;
+            }
+            // 0 when queued, 1 when it's due
+            let charge = 1.0 - ((*frame - frame_counter) as f32/net_info.my_frame_delay.max(1) as f32).clamp(0.0, 1.0);
+            match command {
+                GameCommand::Spawn(SpawnMsgCommand { path, .. }) | GameCommand::UseItem(ItemUse::Decoy(SpawnMsgCommand { path, .. })) => {
+                    self.render_path(_3d, path, p_id, mult);
+                },
+                GameCommand::Target(TargetCommand { path, target, u_id }) => {
+                    self.render_path(_3d, path, p_id, mult);
+                    if let (Target::Blink, Some(u)) = (target, game_state.unit(p_id, *u_id)) {
+                        _3d.draw_line_3D(vec3(u.pos, self.cs.get_f32("cube_z_offset")), vec3(path[0], self.cs.get_f32("cube_z_offset")), c);
+                        _3d.draw_circle_3D(vec3(u.pos, 0.02), (1.0 - charge) * game_state.rules.blink_range, rvec3(0.0, 0.0, 1.0), 0.0, c);
+                    }
+                },
+                GameCommand::Blink(BlinkCommand { u_id }) => {
+                    if let Some(u) = game_state.unit(p_id, *u_id) {
+                        _3d.draw_circle_3D(vec3(u.pos, 0.02), (1.0 - charge) * game_state.rules.blink_range, rvec3(0.0, 0.0, 1.0), 0.0, c);
+                    }
+                },
+                GameCommand::Intercept(InterceptCommand { pos }) => {
+                    self.plane.set_transform(&Matrix::rotate_x(PI/2.0));
+                    _3d.draw_model(&self.plane, vec3(*pos, 0.0) + bring_front, charge, c);
+                },
+                GameCommand::Grapple(GrappleCommand { pos }) => {
+                    let z = self.cs.get_f32("bounty_z");
+                    _3d.draw_line_3D(vec3(*game_state.map.ship(p_id), z), vec3(*pos, z), c);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    pub fn render_path(self: &mut Renderer, _3d: &mut RaylibMode3D<RaylibDrawHandle>, path: &VecDeque<Vector2>, p_id: usize, color_mult: f32) {
         let path_width = 0.5;
         // FIXME bring_front messes with shadows a tiny bit. can put this inside render_map to avoid hack
         let bring_front = rvec3(-0.01, -0.01, 0.01);
-        let c = self.cs.get_p_color("message_color", p_id);
+        let c = scale_color(self.cs.get_p_color("message_color", p_id), color_mult);
         self.shader.set_shader_value(self.locs.emissive_color, self.cs.get_p_color("message_emission", p_id).color_normalize());
         self.shader.set_shader_value(self.locs.emissive_power, self.cs.get_f32(&format!("message_e_power{}", p_id)));
 
@@ -624,7 +760,7 @@ impl Renderer {
         }
     }
 
-    pub fn render(self: &mut Renderer, rl: &mut RaylibHandle, thread: &RaylibThread, frame_counter: i32, game_state: &GameState,
+    pub fn render(self: &mut Renderer, rl: &mut RaylibHandle, thread: &RaylibThread, frame_counter: i32, game_state: &GameState, clock: &SimClock, routes: &RouteCache, templates: &Templates,
             mouse_position: Vector3, mouse_state: &MouseState, state: &ClientState, zoom: bool, net_info: &NetInfo, screen_changed: bool) {
         self.frame_load_constants(rl, thread);
         let p_id = game_state.p_id;
@@ -676,35 +812,69 @@ impl Renderer {
         _3d.set_matrix_projection(&thread, Matrix::identity());
         _3d.set_matrix_modelview(&thread, Renderer::iso_proj(screen_width, screen_height, zoom));
 
-        self.render_map(&mut _3d, mouse_position, &game_state.interceptions, frame_counter);
-        self.render_ships(&mut _3d, game_state, cube_z_offset, cube_side_len, &mut cube, p_id);
-        let packed_b_pos = self.render_messages(&mut _3d, game_state, cube_z_offset, &mut cube, cube_side_len, p_id, frame_counter);
+        self.render_map(&mut _3d, mouse_position, game_state, frame_counter);
+        let unit_pos = clock.unit_positions(game_state);
+        self.render_ships(&mut _3d, game_state, &unit_pos, cube_z_offset, cube_side_len, &mut cube, p_id);
+        let packed_b_pos = self.render_messages(&mut _3d, game_state, &unit_pos, cube_z_offset, &mut cube, cube_side_len, p_id, frame_counter);
         self.render_bounties(&mut _3d, &game_state.bounties, frame_counter, packed_b_pos);
+        self.render_grapples(&mut _3d, game_state);
+        self.render_pending(&mut _3d, game_state, net_info, frame_counter);
 
-        for s in game_state.selection.iter() {
-            if let Selection::Unit(u_id) = s {
-                let mut tmp_path = game_state.my_units[*u_id].path.clone();
-                tmp_path.push_front(game_state.my_units[*u_id].pos);
-                self.render_path(&mut _3d, &tmp_path, p_id);
-            }
+        for u in game_state.units(p_id).iter().filter(|u| game_state.selection.contains(&Selection::Unit(u.id))) {
+            let mut tmp_path = u.path.clone();
+            tmp_path.push_front(u.pos);
+            self.render_path(&mut _3d, &tmp_path, p_id, 1.0);
         }
 
         let mut m_lumber_cost = None;
-        if let MouseState::Path(path, y_first) = mouse_state {
-            let mut tmp_path = path.clone();
-            let p = path[path.len() - 1];
-            let m: Vector2;
-            if *y_first {
-                m = Vector2::new(p.x.round(), mouse_position.y.round());
-            } else {
-                m = Vector2::new(mouse_position.x.round(), p.y.round());
+        let mut m_route_cost = None;
+        if let MouseState::Path(path, y_first, waypoints, kind, dragging) = mouse_state {
+            let lumber_cost = |p: &VecDeque<Vector2>| match *kind {
+                PathKind::Reroute(u_id) => game_state.unit(p_id, u_id)
+                    .and_then(|u| retarget(u, Target::Move, p).map(|r| retarget_lumber_cost(u, &r))),
+                _ => Some(path_lumber_cost(p, &game_state.rules)),
+            };
+            if let Some(route) = &routes.suggested {
+                self.render_path(&mut _3d, route, p_id, self.cs.get_f32("route_color_mult"));
+                m_route_cost = lumber_cost(route);
+            }
+            let bring_front = rvec3(-0.01, -0.01, 0.02);
+            self.plane.set_transform(&Matrix::rotate_x(PI/2.0));
+            for w in waypoints {
+                _3d.draw_model(&self.plane, vec3(*w, 0.0) + bring_front, 0.5, self.cs.get_color("selection"));
+            }
+
+            let mouse_tile = rounded(rvec2(mouse_position.x, mouse_position.y));
+            let tmp_path = match dragging {
+                Some((i, None)) => moved_point(path, *i, mouse_tile, *y_first),
+                _ => {
+                    let mut tmp_path = path.clone();
+                    let p = path[path.len() - 1];
+                    let m: Vector2;
+                    if *y_first {
+                        m = Vector2::new(p.x.round(), mouse_position.y.round());
+                    } else {
+                        m = Vector2::new(mouse_position.x.round(), p.y.round());
+                    }
+                    tmp_path.push_back(m);
+                    tmp_path.push_back(mouse_tile);
+                    tmp_path
+                },
+            };
+            // the points that can be dragged
+            for p in path.iter().skip(1) {
+                _3d.draw_model(&self.plane, vec3(*p, 0.0) + bring_front, 0.25, self.cs.get_color("selection"));
             }
-            tmp_path.push_back(m);
-            tmp_path.push_back(Vector2::new(mouse_position.x.round(), mouse_position.y.round()));
-            self.render_path(&mut _3d, &tmp_path, p_id);
-            m_lumber_cost = Some(path_lumber_cost(&tmp_path));
+            let decoy = *kind == PathKind::Decoy;
+            self.render_path(&mut _3d, &tmp_path, p_id, if decoy { self.cs.get_f32("decoy_color_mult") } else { 1.0 });
+            if !decoy {
+                m_lumber_cost = lumber_cost(&tmp_path);
+            }
+        }
+        if let Some(MouseState::Path(path, ..)) = mouse_state.resume() {
+            self.render_path(&mut _3d, path, p_id, self.cs.get_f32("route_color_mult"));
         }
-        if let MouseState::Intercept = mouse_state {
+        if let MouseState::Intercept(_) = mouse_state {
             // FIXME bring_front messes with shadows a tiny bit. can put this inside render_map to avoid hack
             let bring_front = rvec3(-0.01, -0.01, 0.01);
             let c = self.cs.get_p_color("message_color", p_id);
@@ -713,6 +883,29 @@ impl Renderer {
             self.plane.set_transform(&Matrix::rotate_x(PI/2.0));
             _3d.draw_model(&self.plane, vec3(rounded(rvec2(mouse_position.x, mouse_position.y)), 0.0) + bring_front, 1.0, c);
         }
+        if let MouseState::Blink(u_id, _) = mouse_state {
+            if let Some(u) = game_state.unit(p_id, *u_id) {
+                let target = rounded(rvec2(mouse_position.x, mouse_position.y));
+                let in_range = (target - u.pos).length() <= game_state.rules.blink_range;
+                let c = scale_color(self.cs.get_p_color("message_color", p_id), if in_range { 1.0 } else { self.cs.get_f32("route_color_mult") });
+                _3d.draw_circle_3D(vec3(u.pos, 0.02), game_state.rules.blink_range, rvec3(0.0, 0.0, 1.0), 0.0, c);
+                if in_range {
+                    if let Some(path) = &routes.blink {
+                        self.render_path(&mut _3d, path, p_id, 1.0);
+                    }
+                }
+            }
+        }
+        if let MouseState::Grapple(_) = mouse_state {
+            let bring_front = rvec3(-0.01, -0.01, 0.01);
+            let target = rounded(rvec2(mouse_position.x, mouse_position.y));
+            let ship = *game_state.map.ship(p_id);
+            let in_range = (target - ship).length() <= game_state.rules.grapple_range;
+            let c = scale_color(self.cs.get_p_color("message_color", p_id), if in_range { 1.0 } else { self.cs.get_f32("route_color_mult") });
+            _3d.draw_line_3D(vec3(ship, self.cs.get_f32("bounty_z")), vec3(target, self.cs.get_f32("bounty_z")), c);
+            self.plane.set_transform(&Matrix::rotate_x(PI/2.0));
+            _3d.draw_model(&self.plane, vec3(target, 0.0) + bring_front, 1.0, c);
+        }
 
         drop(_3d);
 
@@ -736,27 +929,50 @@ impl Renderer {
         text_pos += gap;
         if let Some(lumber_cost) = m_lumber_cost {
             _d.draw_text(&format!("Cost: {}", lumber_cost), text_pos.x.round() as i32, text_pos.y.round() as i32, text_size.round() as i32, Color::WHITE);
+            text_pos += gap;
+        }
+        if let Some(route_cost) = m_route_cost {
+            _d.draw_text(&format!("Route (R): {}", route_cost), text_pos.x.round() as i32, text_pos.y.round() as i32, text_size.round() as i32, Color::WHITE);
         }
-
-        text_pos = Vector2::new(sh/50.0, screen_height as f32) - gap.scale_by(6.0);
 
         let mut ui_text = vec![];
-        ui_text.push(format!("Gold: {}/{}", game_state.gold[p_id].round(), game_state.gold[(p_id + 1) % 2].round()));
-        ui_text.push(format!("Lumber: {}/{}", game_state.lumber[p_id], game_state.lumber[(p_id + 1) % 2]));
-        ui_text.push(format!("Fuel: {}/{}", (game_state.fuel[p_id] * 100)/START_FUEL, (game_state.fuel[(p_id + 1) % 2] * 100)/START_FUEL));
-        ui_text.push(format!("K/D: {}/{}", game_state.intercepted[p_id], game_state.intercepted[(p_id + 1) % 2]));
+        // ours first, then everyone else's in player order
+        let order: Vec<usize> = std::iter::once(p_id).chain((0..game_state.players()).filter(|p| *p != p_id)).collect();
+        let join = |f: &dyn Fn(usize) -> String| order.iter().map(|p| f(*p)).collect::<Vec<String>>().join("/");
+        ui_text.push(format!("Gold: {}", join(&|p| game_state.gold[p].round().to_string())));
+        ui_text.push(format!("Lumber: {}", join(&|p| game_state.lumber[p].to_string())));
+        let start_fuel = game_state.rules.start_fuel;
+        ui_text.push(format!("Fuel: {}", join(&|p| ((game_state.fuel[p] * 100)/start_fuel).to_string())));
+        ui_text.push(format!("K/D: {}/{}", game_state.intercepted[p_id], game_state.lost[p_id]));
+        ui_text.push(format!("Decoy (D): {} Shield (E): {} Fuel (F): {} Reveal (V): {}{}",
+            game_state.item_count(p_id, Item::Decoy), game_state.item_count(p_id, Item::Shield),
+            game_state.item_count(p_id, Item::FuelCanister), game_state.item_count(p_id, Item::Reveal),
+            if game_state.reveal[p_id] > 0 { format!(" ({}s)", game_state.reveal[p_id]/60) } else { String::new() }));
+        let groups: Vec<String> = (0..CONTROL_GROUPS).filter(|g| !game_state.groups[*g].is_empty()).map(|g| g.to_string()).collect();
+        if !groups.is_empty() {
+            ui_text.push(format!("Groups: {}", groups.join(" ")));
+        }
+        ui_text.push(format!("Grapple (G): {}", if game_state.grapple_cooldown[p_id] > 0 { format!("{}s", game_state.grapple_cooldown[p_id]/60 + 1) } else { "ready".to_string() }));
+        if let Some(e) = &templates.error {
+            ui_text.push(format!("Templates: {}", e));
+        }
+        text_pos = Vector2::new(sh/50.0, screen_height as f32) - gap.scale_by(ui_text.len() as f32 + 2.0);
         let mut max_width = ui_text.iter().fold(0f32, |acc, s| acc.max(default_font.measure_text(s, text_size, text_size/10.0).x));
 
         max_width += text_pos.x + sh/100.0;
         let pad_y = sh/100.0;
         let neg_x_off = 20.0;
-        _d.draw_rectangle_rounded(Rectangle { x: -neg_x_off, y: text_pos.y - pad_y, width: max_width + neg_x_off, height: gap.scale_by(4.0).y + pad_y * 2.0 },
+        _d.draw_rectangle_rounded(Rectangle { x: -neg_x_off, y: text_pos.y - pad_y, width: max_width + neg_x_off, height: gap.scale_by(ui_text.len() as f32).y + pad_y * 2.0 },
             0.1, 10, Color::BLACK.alpha(0.5));
 
         for s in ui_text {
             _d.draw_text(&s, text_pos.x as i32, text_pos.y as i32, text_size as i32, Color::WHITE);
             text_pos += gap;
         }
+
+        if shop_open(game_state) {
+            self.render_shop(&mut _d, game_state, raw_mouse_position, screen_width, screen_height);
+        }
     }
 }
 
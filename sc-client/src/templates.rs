@@ -0,0 +1,71 @@
+use std::collections::{HashMap, VecDeque};
+use raylib::prelude::Vector2;
+use serde::{Deserialize, Serialize};
+
+static TEMPLATE_DIR: &str = "templates";
+pub static TEMPLATE_SLOTS: usize = 9;
+
+// What templates/<map id>.json contains. Paths are [x, y] tiles starting at the ship, keyed by player id since
+// every player starts somewhere else.
+#[derive(Default, Serialize, Deserialize)]
+struct TemplateFile {
+    players: HashMap<usize, Vec<Option<Vec<[i32; 2]>>>>,
+}
+
+// Paths saved into numbered slots. A path that doesn't end at the station is picked up again for drawing.
+pub struct Templates {
+    map_id: String,
+    file: TemplateFile,
+    // the last path we sent, what saving outside of drawing a path saves
+    pub last: Option<VecDeque<Vector2>>,
+    // the last load or save that failed, shown in the UI until a save goes through
+    pub error: Option<String>,
+}
+
+impl Templates {
+    fn path(map_id: &str) -> String {
+        format!("{}/{}.json", TEMPLATE_DIR, map_id)
+    }
+
+    // Starts out empty if there is no file for the map yet
+    pub fn load(map_id: &str) -> Result<Templates, String> {
+        let path = Templates::path(map_id);
+        let file = if std::path::Path::new(&path).exists() {
+            let s = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            serde_json::from_str(&s).map_err(|e| format!("{}: {}", path, e))?
+        } else {
+            TemplateFile::default()
+        };
+        Ok(Templates { map_id: map_id.to_string(), file, last: None, error: None })
+    }
+
+    // No templates, for when the file couldn't be loaded. Saving a slot writes a fresh file over it.
+    pub fn empty(map_id: &str, error: String) -> Templates {
+        Templates { map_id: map_id.to_string(), file: TemplateFile::default(), last: None, error: Some(error) }
+    }
+
+    fn save(self: &Self) -> Result<(), String> {
+        let path = Templates::path(&self.map_id);
+        std::fs::create_dir_all(TEMPLATE_DIR).map_err(|e| format!("{}: {}", TEMPLATE_DIR, e))?;
+        let s = serde_json::to_string_pretty(&self.file).map_err(|e| format!("{}: {}", path, e))?;
+        std::fs::write(&path, s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn get(self: &Self, p_id: usize, slot: usize) -> Option<VecDeque<Vector2>> {
+        self.file.players.get(&p_id)
+            .and_then(|slots| slots.get(slot).cloned().flatten())
+            .map(|path| path.iter().map(|t| Vector2::new(t[0] as f32, t[1] as f32)).collect())
+    }
+
+    // Writes the file straight away so templates survive a crash
+    pub fn set(self: &mut Self, p_id: usize, slot: usize, path: &VecDeque<Vector2>) -> Result<(), String> {
+        let slots = self.file.players.entry(p_id).or_insert_with(|| vec![None; TEMPLATE_SLOTS]);
+        slots.resize(TEMPLATE_SLOTS, None);
+        slots[slot] = Some(path.iter().map(|v| [v.x.round() as i32, v.y.round() as i32]).collect());
+        self.save()
+    }
+
+    pub fn filled(self: &Self, p_id: usize) -> Vec<usize> {
+        (0..TEMPLATE_SLOTS).filter(|s| self.get(p_id, *s).is_some()).collect()
+    }
+}
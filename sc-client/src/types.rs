@@ -1,3 +1,5 @@
+use sc_types::GameCommand;
+
 use crate::{TimeWindowAvg, WindowAvg};
 
 #[derive(Debug)]
@@ -13,4 +15,6 @@ pub struct NetInfo<'a> {
     pub game_ps: &'a TimeWindowAvg,
     pub waiting_avg: &'a WindowAvg,
     pub my_frame_delay: u8,
+    // see NetState::pending
+    pub pending: Vec<(i32, &'a GameCommand)>,
 }
\ No newline at end of file
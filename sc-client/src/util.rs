@@ -1,9 +1,9 @@
 extern crate rmp_serde as rmps;
 
-use std::{collections::{HashMap, VecDeque}, hash::Hash, net::{SocketAddr, UdpSocket}, ops::AddAssign, slice::Iter, time::Instant};
+use std::{collections::{HashMap, VecDeque}, net::{SocketAddr, UdpSocket}, slice::Iter, time::Instant};
 use num_traits::Zero;
 use raylib::{color::{rcolor, Color}, math::{Vector2, Vector3}};
-use sc_types::{ClientPkt, SeqState, ServerEnum, ServerPkt};
+use sc_types::{ClientPkt, GameState, SeqState, ServerEnum, ServerPkt};
 use std::io;
 
 pub fn scale_color(a: Color, s: f32) -> Color {
@@ -19,18 +19,6 @@ pub fn vec2(v3: Vector3) -> Vector2 {
     Vector2::new(v3.x, v3.y)
 }
 
-pub fn rounded(v: Vector2) -> Vector2 {
-    Vector2::new(v.x.round(), v.y.round())
-}
-
-pub fn hm_add<K: Hash + Clone + Copy + Eq, V: AddAssign + Copy + Clone>(a: HashMap<K, V>, b: &HashMap<K, V>) -> HashMap<K, V> {
-    let mut out = a.clone();
-    for (k, v) in b.iter() {
-        out.entry(*k).and_modify(|e| *e += *v).or_insert(*v);
-    }
-    out
-}
-
 // TODO move this to impl SeqState
 pub fn socket_recv(socket: &UdpSocket, expected_addr: &SocketAddr, seq_state: &mut SeqState) -> Option<ServerEnum> {
     let mut buf = [0u8; 16000];
@@ -145,6 +133,66 @@ impl TimeWindowAvg {
     }
 }
 
+// Wall time worth of steps we run at most per render frame. Past that the match slows down instead of freezing
+// the window to catch up.
+static MAX_CATCH_UP_STEPS: u32 = 5;
+// Units that moved further than this in one step blinked and are drawn where they are instead of sliding there
+static MAX_LERP_D: f32 = 1.0;
+
+// Runs the sim at frame_rate steps a second however fast we render
+pub struct SimClock {
+    last: Instant,
+    acc: f64,
+    step: f64,
+    // position of every unit before the last step by Unit::id
+    prev: HashMap<usize, Vector2>,
+}
+
+impl SimClock {
+    pub fn new(frame_rate: u32) -> SimClock {
+        SimClock {
+            last: Instant::now(),
+            acc: 0f64,
+            step: 1f64/frame_rate as f64,
+            prev: HashMap::new(),
+        }
+    }
+
+    // Adds the wall time since the last call
+    pub fn tick(self: &mut Self) {
+        let now = Instant::now();
+        self.acc = (self.acc + now.duration_since(self.last).as_secs_f64()).min(self.step * MAX_CATCH_UP_STEPS as f64);
+        self.last = now;
+    }
+
+    pub fn due(self: &Self) -> bool {
+        self.acc >= self.step
+    }
+
+    // Call right before stepping game_state
+    pub fn stepping(self: &mut Self, game_state: &GameState) {
+        self.acc -= self.step;
+        self.prev = game_state.all_units().map(|u| (u.id, u.pos)).collect();
+    }
+
+    // How far we are from the last step to the next one
+    pub fn alpha(self: &Self) -> f32 {
+        (self.acc / self.step).clamp(0f64, 1f64) as f32
+    }
+
+    // Where to draw every unit by Unit::id, between where it was before the last step and where it is now
+    pub fn unit_positions(self: &Self, game_state: &GameState) -> HashMap<usize, Vector2> {
+        let alpha = self.alpha();
+        game_state.all_units().map(|u| {
+            let pos = match self.prev.get(&u.id) {
+                Some(prev) if (u.pos - *prev).length() <= MAX_LERP_D => prev.lerp(u.pos, alpha),
+                _ => u.pos,
+            };
+            (u.id, pos)
+        }).collect()
+    }
+}
+
 pub struct WindowAvg {
     history: Vec<f64>,
     index: usize,
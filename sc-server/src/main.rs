@@ -2,6 +2,9 @@ use async_std::io;
 use async_std::net::UdpSocket;
 use async_std::task;
 use sc_types::*;
+use sc_types::map::{Map, DEFAULT_MAP_ID};
+use sc_types::ruleset::Ruleset;
+use std::env;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::Instant;
@@ -11,10 +14,32 @@ use rand_core::*;
 enum ServerState {
     Waiting,
     Started,
-    Ended(SocketAddr),
+    // peers that have reported the game ended
+    Ended(Vec<SocketAddr>),
 }
 
 fn main() -> io::Result<()> {
+    let ruleset = Ruleset::load_or_default("ruleset.json").unwrap_or_else(|e| {
+        println!("Unable to load ruleset {}", e);
+        std::process::exit(1);
+    });
+    let ruleset_hash = ruleset.hash();
+    // the lobby is just the server, whoever starts it picks the map and mode
+    let args: Vec<String> = env::args().collect();
+    let map = Map::load(args.get(1).map(|s| &s[..]).unwrap_or(DEFAULT_MAP_ID)).unwrap_or_else(|e| {
+        println!("Unable to load map {}", e);
+        std::process::exit(1);
+    });
+    let mode = match args.get(2) {
+        Some(s) => Mode::from_arg(s).unwrap_or_else(|| {
+            println!("Usage: sc-server [map] [ffa|teams]");
+            std::process::exit(1);
+        }),
+        None => Mode::Ffa,
+    };
+    // the game starts once every seat on the map is taken
+    let players = map.players();
+
     task::block_on(async {
         let socket = UdpSocket::bind("0.0.0.0:8080").await?;
         let mut buf = [0u8; 16000];
@@ -34,21 +59,21 @@ fn main() -> io::Result<()> {
                 },
                 Err(e) => panic!("{:?}", e)
             };
+            // only peers that said Hello have a seat
+            let seated = conn_states.values().filter(|(_, p_id)| p_id.is_some()).count();
+            if conn_states.get(&peer).map(|(_, p_id)| p_id.is_none()).unwrap_or(true) && seated >= players {
+                println!("Lobby is full, ignoring {}", peer);
+                continue;
+            }
             conn_states.entry(peer).or_insert((SeqState::new(), None));
 
             match req {
                 ClientPkt::Hello { seq, sent_time } => {
                     let p_id = if let Some(assigned_p_id) = conn_states.get(&peer).unwrap().1 { 
                         assigned_p_id
-                    } else if conn_states.len() == 1{
-                        0
                     } else {
-                        let other = conn_states.iter().find(|(k, _)| *k != &peer).unwrap().1;
-                        if let Some(other_p_id) = other.1 {
-                            (other_p_id + 1) % 2
-                        } else {
-                            0
-                        }
+                        // smallest id nobody else has
+                        (0..players).find(|p_id| !conn_states.values().any(|(_, other_p_id)| *other_p_id == Some(*p_id))).unwrap()
                     };
 
                     conn_states.entry(peer).and_modify(|v| v.1 = Some(p_id));
@@ -72,6 +97,11 @@ fn main() -> io::Result<()> {
                     }
                 },
                 ClientPkt::Target { seq, ack, updates, frame, frame_ack, frame_delay } => {
+                    // relaying commands from a peer without a seat would put them in someone else's stream
+                    let player_id = match conn_states.get(&peer).unwrap().1 {
+                        Some(player_id) => player_id,
+                        None => continue,
+                    };
                     let r_seq_state = &mut conn_states.get_mut(&peer).expect("Peer not in hashmap").0;
                     r_seq_state.recv(seq, ack).map(|e| { println!("recvd target err: {}", e); });
                     match state {
@@ -82,7 +112,7 @@ fn main() -> io::Result<()> {
                                         seq: s_seq_state.send_seq,
                                         ack: s_seq_state.send_ack,
                                         server_time: instant.elapsed().as_secs_f64(),
-                                        msg: ServerEnum::UpdateOtherTarget { updates: updates.clone(), frame, frame_ack, frame_delay },
+                                        msg: ServerEnum::UpdateOtherTarget { player_id, updates: updates.clone(), frame, frame_ack, frame_delay },
                                     };
                                     match  rmp_serde::encode::to_vec(&server_pkt) {
                                         Ok(buf) => {
@@ -101,12 +131,15 @@ fn main() -> io::Result<()> {
                 ClientPkt::Ended { seq, ack, frame: _ } => {
                     let r_seq_state = &mut conn_states.get_mut(&peer).expect("Peer not in hashmap").0;
                     r_seq_state.recv(seq, ack);
-                    match state {
+                    match &mut state {
                         ServerState::Started => {
-                            state = ServerState::Ended(peer)
+                            state = ServerState::Ended(vec![peer])
                         },
-                        ServerState::Ended(ended_addr) => {
-                            if peer != ended_addr {
+                        ServerState::Ended(ended_addrs) => {
+                            if !ended_addrs.contains(&peer) {
+                                ended_addrs.push(peer);
+                            }
+                            if ended_addrs.len() >= conn_states.len() {
                                 state_hashes.clear();
                                 conn_states.clear();
                                 state = ServerState::Waiting
@@ -152,15 +185,15 @@ fn main() -> io::Result<()> {
 
             match state {
                 ServerState::Waiting => {
-                    if conn_states.len() >= 2 {
+                    if conn_states.values().filter(|(_, p_id)| p_id.is_some()).count() >= players {
                         let rng = ChaCha20Rng::from_entropy();
                         instant = Instant::now();
-                        for (peer, (seq_state, _)) in conn_states.iter_mut() {
+                        for (peer, (seq_state, _)) in conn_states.iter_mut().filter(|(_, (_, p_id))| p_id.is_some()) {
                             let server_pkt = ServerPkt {
                                 seq: seq_state.send_seq,
                                 ack: seq_state.send_ack,
                                 server_time: instant.elapsed().as_secs_f64(),
-                                msg: ServerEnum::Start { rng_seed: rng.get_seed() }
+                                msg: ServerEnum::Start { rng_seed: rng.get_seed(), ruleset: ruleset.clone(), ruleset_hash, map_id: map.id.clone(), map_hash: map.hash, mode }
                             };
                             match  rmp_serde::encode::to_vec(&server_pkt) {
                                 Ok(buf) => {
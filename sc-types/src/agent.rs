@@ -0,0 +1,65 @@
+use rand_chacha::ChaCha20Rng;
+
+use crate::*;
+use crate::ai::{Ai, AiLevel};
+use crate::map::Map;
+use crate::ruleset::Ruleset;
+use crate::sim::{game_over, step};
+
+// Anything that can play a side of the game: the sandbox opponent, a network bot or a headless match.
+pub trait Agent {
+    // Commands player p_id issues on this frame. view is the full simulation state, the agent should
+    // only look at what that player would see.
+    fn decide(&mut self, view: &GameState, p_id: usize, frame: i32) -> Vec<GameCommand>;
+}
+
+// Never does anything, this is what sandbox used to play against
+pub struct Idle;
+
+impl Agent for Idle {
+    fn decide(&mut self, _view: &GameState, _p_id: usize, _frame: i32) -> Vec<GameCommand> {
+        vec![]
+    }
+}
+
+// none|easy|medium|hard
+pub fn agent_from_arg(s: &str, rng: ChaCha20Rng) -> Option<Box<dyn Agent>> {
+    if s == "none" {
+        Some(Box::new(Idle))
+    } else {
+        AiLevel::from_arg(s).map(|level| Box::new(Ai::new(level, rng)) as Box<dyn Agent>)
+    }
+}
+
+pub struct MatchResult {
+    // None for a draw or if max_frames was reached
+    // the winning team
+    pub winner: Option<usize>,
+    pub frames: i32,
+    pub fuel: Vec<i32>,
+    pub intercepted: Vec<u8>,
+}
+
+// Plays a whole game without rendering or networking, agents[p] plays player p so there must be
+// one per player on the map. All commands take effect on the frame they are issued.
+pub fn play_match(agents: &mut Vec<Box<dyn Agent>>, rng: ChaCha20Rng, rules: Ruleset, map: Map, mode: Mode, max_frames: i32) -> MatchResult {
+    assert_eq!(agents.len(), map.players());
+    let mut game_state = GameState::new(0, rng, rules, map, mode);
+    let mut frame = 0;
+    let mut winner = None;
+    while frame < max_frames {
+        let updates: Vec<Vec<GameCommand>> = agents.iter_mut().enumerate().map(|(p_id, a)| a.decide(&game_state, p_id, frame)).collect();
+        step(&mut game_state, &updates.iter().collect::<Vec<_>>(), frame);
+        frame += 1;
+        if let Some(w) = game_over(&game_state) {
+            winner = w;
+            break;
+        }
+    }
+    MatchResult {
+        winner,
+        frames: frame,
+        fuel: game_state.fuel,
+        intercepted: game_state.intercepted,
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use raylib::prelude::Vector2;
+
+use crate::*;
+use crate::agent::Agent;
+use crate::route::route;
+use crate::ruleset::{Ruleset, Stat};
+use crate::sim::{path_lumber_cost, rounded};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AiLevel {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl AiLevel {
+    pub fn from_arg(s: &str) -> Option<AiLevel> {
+        match s {
+            "easy" => Some(AiLevel::Easy),
+            "medium" => Some(AiLevel::Medium),
+            "hard" => Some(AiLevel::Hard),
+            _ => None
+        }
+    }
+
+    // frames between decisions
+    fn think_interval(self) -> i32 {
+        match self {
+            AiLevel::Easy => 90,
+            AiLevel::Medium => 45,
+            AiLevel::Hard => 15,
+        }
+    }
+
+    fn max_units(self) -> usize {
+        match self {
+            AiLevel::Easy => 2,
+            AiLevel::Medium => 4,
+            AiLevel::Hard => 8,
+        }
+    }
+
+    fn max_interceptions(self) -> usize {
+        match self {
+            AiLevel::Easy => 1,
+            AiLevel::Medium => 2,
+            AiLevel::Hard => 3,
+        }
+    }
+
+    // how many tiles an intercept guess can be off by
+    fn intercept_error(self) -> i32 {
+        match self {
+            AiLevel::Easy => 2,
+            AiLevel::Medium => 1,
+            AiLevel::Hard => 0,
+        }
+    }
+
+    // gold kept for interceptions before buying upgrades
+    fn gold_reserve(self, rules: &Ruleset) -> f32 {
+        match self {
+            AiLevel::Easy => 4f32 * rules.intercept_cost,
+            AiLevel::Medium => 2f32 * rules.intercept_cost,
+            AiLevel::Hard => rules.intercept_cost,
+        }
+    }
+}
+
+// Position of the unit after travelling dist along its path, None if it arrives before that.
+fn advance(unit: &Unit, dist: f32) -> Option<Vector2> {
+    let mut remaining = dist;
+    let mut pos = unit.pos;
+    for p in unit.path.iter() {
+        let l = (*p - pos).length();
+        if l > remaining {
+            return Some(pos.lerp(*p, remaining/l));
+        }
+        remaining -= l;
+        pos = *p;
+    }
+    None
+}
+
+pub struct Ai {
+    pub level: AiLevel,
+    rng: ChaCha20Rng,
+}
+
+impl Agent for Ai {
+    fn decide(self: &mut Self, view: &GameState, p_id: usize, frame: i32) -> Vec<GameCommand> {
+        let mut out = vec![];
+        if frame % self.level.think_interval() != 0 {
+            return out;
+        }
+        let mut gold = view.gold[p_id];
+
+        if view.spawn_cooldown[p_id] <= 0 && view.units(p_id).len() < self.level.max_units() {
+            let path = self.plan_path(view, p_id);
+            if view.lumber[p_id] >= path_lumber_cost(&path, &view.rules) {
+                out.push(GameCommand::Spawn(SpawnMsgCommand { player_id: p_id, path }));
+            }
+        }
+
+        out.append(&mut self.intercepts(view, p_id, frame, &mut gold));
+
+        if self.level != AiLevel::Easy {
+            out.append(&mut self.blinks(view, p_id));
+        }
+
+        for u in Upgrade::ALL {
+            if !view.upgrades[p_id].contains(&u) && gold - u.cost(&view.rules) >= self.level.gold_reserve(&view.rules) {
+                gold -= u.cost(&view.rules);
+                out.push(GameCommand::BuyUpgrade(u));
+            }
+        }
+        out
+    }
+}
+
+impl Ai {
+    pub fn new(level: AiLevel, rng: ChaCha20Rng) -> Ai {
+        Ai { level, rng }
+    }
+
+    // Axis aligned path from the ship to the station, optionally detouring through a bounty.
+    // At most 3 turns so it never costs lumber, unless it has to go around blocked tiles.
+    fn plan_path(self: &mut Self, game_state: &GameState, p_id: usize) -> VecDeque<Vector2> {
+        let start = *game_state.map.ship(p_id);
+        let stations = game_state.map.station(p_id);
+        let end = stations[self.rng.gen_range(0..stations.len())];
+        let detour = |b: &Bounty| (b.pos - start).length() + (end - b.pos).length();
+        let m_bounty = match self.level {
+            _ if game_state.bounties.is_empty() => None,
+            AiLevel::Easy => None,
+            AiLevel::Medium => Some(game_state.bounties[self.rng.gen_range(0..game_state.bounties.len())].pos),
+            AiLevel::Hard => game_state.bounties.iter().min_by(|a, b| detour(a).total_cmp(&detour(b))).map(|b| b.pos),
+        };
+
+        let mut path = VecDeque::from(vec![start]);
+        let mut push = |p: Vector2| {
+            if *path.back().unwrap() != p {
+                path.push_back(p);
+            }
+        };
+        if let Some(b) = m_bounty {
+            push(Vector2::new(b.x, start.y));
+            push(b);
+            push(Vector2::new(end.x, b.y));
+        } else {
+            push(Vector2::new(end.x, start.y));
+        }
+        push(end);
+
+        let map = &game_state.map;
+        if path.iter().zip(path.iter().skip(1)).any(|(a, b)| map.crosses_blocked(a, b)) {
+            let waypoints: Vec<Vector2> = m_bounty.into_iter().collect();
+            if let Some(r) = route(&map.area, start, None, &[end], &waypoints, &map.blocked) {
+                return r;
+            }
+        }
+        path
+    }
+
+    fn intercepts(self: &mut Self, game_state: &GameState, p_id: usize, frame: i32, gold: &mut f32) -> Vec<GameCommand> {
+        let mut out = vec![];
+        let rules = &game_state.rules;
+        let mut active = game_state.interceptions.iter().filter(|i| i.player_id == p_id && frame - i.start_frame < i.delay).count();
+        // aim for the middle of the window in which the interception kills
+        let lead = (game_state.stat(p_id, Stat::InterceptDelay) + game_state.stat(p_id, Stat::InterceptExpiry)/2) as f32;
+        let err = self.level.intercept_error();
+        for unit in game_state.enemy_units(p_id) {
+            if active >= self.level.max_interceptions() || *gold < rules.intercept_cost {
+                break;
+            }
+            if let Some(p) = advance(unit, unit.speed(rules, &game_state.map) * lead) {
+                let guess = rounded(p) + Vector2::new(self.rng.gen_range(-err..=err) as f32, self.rng.gen_range(-err..=err) as f32);
+                if game_state.map.area.contains_point(&guess) && !game_state.visible_interceptions(p_id).iter().any(|i| i.pos == guess) {
+                    out.push(GameCommand::Intercept(InterceptCommand { pos: guess }));
+                    *gold -= rules.intercept_cost;
+                    active += 1;
+                }
+            }
+        }
+        out
+    }
+
+    // Blink over enemy interceptions that are about to go off in front of a unit
+    fn blinks(self: &Self, game_state: &GameState, p_id: usize) -> Vec<GameCommand> {
+        let mut out = vec![];
+        for unit in game_state.units(p_id) {
+            if unit.blink_cooldown > 0 || unit.blinking.is_none() {
+                continue;
+            }
+            let threatened = game_state.visible_interceptions(p_id).iter().filter(|i| game_state.enemies(i.player_id, p_id)).any(|i|
+                (1..=game_state.rules.blink_range as i32).any(|d| advance(unit, d as f32).map(|p| i.covers(rounded(p))).unwrap_or(false)));
+            if threatened {
+                out.push(GameCommand::Blink(BlinkCommand { u_id: unit.id }));
+            }
+        }
+        out
+    }
+}
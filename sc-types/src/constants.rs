@@ -1,67 +1,34 @@
-use crate::shapes::*;
 use raylib::{color::Color, prelude::Vector2};
 
 #[derive(Eq, PartialEq, Hash)]
 pub enum AreaEnum {
-    P0Spawn,
-    P1Spawn,
-    P0Station,
-    P1Station,
+    // player id
+    Spawn(usize),
+    Station(usize),
     Blocked
 }
 
-pub static START_FUEL: i32 = 1000 * 60;
-pub static FUEL_LOSS: i32 = 5; // per frame
-pub static PASSIVE_GOLD_GAIN: f32 = 0f32/60f32;
-pub static STARTING_GOLD: f32 = 300f32;
-pub static STARTING_LUMBER: i32 = 20;
-pub static MSG_COOLDOWN: i32 = 10 * 60;
-pub static MSG_FREE_LUMBER: i32 = 3;
-pub static MSG_FUEL: i32 = FUEL_LOSS * 60 * 15;
 pub static MSG_BUBBLE_LEN: f32 = 80f32;
 pub static MSG_BUBBLE_WIDTH: f32 = MESSAGE_SIZE.x;
-pub static MSG_DELIVERY_GOLD_BOUNTY: f32 = 0f32;
-pub static MESSAGE_SPEED: f32 = 1.0/60.0;
 pub static MESSAGE_SIZE: &Vector2 = &Vector2 { x: 20f32, y: 20f32 };
 pub static BOUNTY_SIZE: &Vector2 = &Vector2 { x: 20f32, y: 20f32 };
-pub static BLINK_COOLDOWN: i32 = 900;
 pub static INTERCEPT_LENGTH: f32 = 30f32;
-pub static INTERCEPT_COST: f32 = 100f32;
-pub static INTERCEPT_EXPIRY: i32 = 2 * 60;
-pub static INTERCEPT_DELAY: i32 = 4 * 60;
-pub static BLINK_RANGE: f32 = 3f32;
-pub static KILLS_TO_WIN: u8 = 5;
-pub static PLAY_AREA: Rect<i32> = Rect {
-    x: -12, y: -12,
-    w: 24, h: 24,
-};
-pub static SHIPS: [Vector2; 2] = [Vector2 { x: -12.0, y: 11.0 }, Vector2 { x: -11.0, y: 12.0 }];
-pub static STATIONS: [[Vector2; 4]; 2] = [
-    [Vector2 { x: 11.0, y: -12.0 }, Vector2 { x: 10.0, y: -12.0 }, Vector2 { x: 9.0, y: -12.0 }, Vector2 { x: 8.0, y: -12.0 }],
-    [Vector2 { x: 12.0, y: -11.0 }, Vector2 { x: 12.0, y: -10.0 }, Vector2 { x: 12.0, y: -9.0 }, Vector2 { x: 12.0, y: -8.0 }]
-];
-
-pub fn station(p_id: usize) -> &'static [Vector2; 4] {
-    &STATIONS[p_id]
-}
-
-pub fn ship(p_id: usize) -> &'static Vector2 {
-    &SHIPS[p_id]
-}
 
 pub fn ship_color(p_id: usize) -> Color {
-    if p_id == 0 {
-        Color::BLUE
-    } else {
-        Color::RED
+    match p_id {
+        0 => Color::BLUE,
+        1 => Color::RED,
+        2 => Color::GREEN,
+        _ => Color::ORANGE,
     }
 }
 
 pub fn message_color(p_id: usize) -> Color {
-    if p_id == 0 {
+    match p_id {
         // 3a86ff
-        Color::from_hex("2510fd").unwrap()
-    } else {
-        Color::from_hex("780000").unwrap()
+        0 => Color::from_hex("2510fd").unwrap(),
+        1 => Color::from_hex("780000").unwrap(),
+        2 => Color::from_hex("007f5f").unwrap(),
+        _ => Color::from_hex("b5651d").unwrap(),
     }
 }
\ No newline at end of file
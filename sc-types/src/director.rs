@@ -0,0 +1,229 @@
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use raylib::prelude::Vector2;
+
+use crate::*;
+use crate::map::{BountyZone, Map};
+use crate::sim::same_tile;
+
+// Tries per spawn to find a free tile before giving up until the next one
+static MAX_TILE_TRIES: usize = 100;
+
+// Index into weights picked with probability weight/sum, weights <= 0 are never picked.
+// None if there is nothing to pick.
+pub fn weighted_index(rng: &mut ChaCha20Rng, weights: &[f32]) -> Option<usize> {
+    let total: f32 = weights.iter().filter(|w| **w > 0.0).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut r = rng.gen_range(0.0..total);
+    for (i, w) in weights.iter().enumerate() {
+        if *w <= 0.0 {
+            continue;
+        }
+        if r < *w {
+            return Some(i);
+        }
+        r -= *w;
+    }
+    // rounding can leave r a hair above the last weight
+    weights.iter().rposition(|w| *w > 0.0)
+}
+
+pub fn weighted_choice<'a, T>(rng: &mut ChaCha20Rng, items: &'a [(T, f32)]) -> Option<&'a T> {
+    let weights: Vec<f32> = items.iter().map(|(_, w)| *w).collect();
+    weighted_index(rng, &weights).map(|i| &items[i].0)
+}
+
+fn bounty_counts(bounties: &Vec<Bounty>) -> Vec<(BountyEnum, usize)> {
+    BountyEnum::ALL.iter().map(|b_type| (*b_type, bounties.iter().filter(|b| b.type_ == *b_type).count())).collect()
+}
+
+// The map's bounty zones, or the whole board if it has none
+fn zones(map: &Map) -> Vec<BountyZone> {
+    if map.bounty_zones.is_empty() {
+        vec![BountyZone { area: map.area, weight: 1.0, types: vec![] }]
+    } else {
+        map.bounty_zones.clone()
+    }
+}
+
+fn random_tile(rng: &mut ChaCha20Rng, zone: &BountyZone) -> Vector2 {
+    let a = &zone.area;
+    Vector2::new(rng.gen_range(a.x..=(a.x + a.w)) as f32, rng.gen_range(a.y..=(a.y + a.h)) as f32)
+}
+
+// Type weights from the ruleset at the current time. Types already on the board are made less likely by
+// rarity_bias, types no zone allows can't be picked.
+fn type_weights(game_state: &GameState, zones: &[BountyZone], t: f32) -> Vec<(BountyEnum, f32)> {
+    let director = &game_state.rules.bounty_director;
+    let counts = bounty_counts(&game_state.bounties);
+    let total = game_state.bounties.len();
+    counts.iter().map(|(b_type, count)| {
+        let share = if total == 0 { 0.0 } else { *count as f32 / total as f32 };
+        let allowed = zones.iter().any(|z| z.weight > 0.0 && z.allows(*b_type));
+        let w = if allowed { director.weights.get(*b_type).at(t) * (1.0 - director.rarity_bias * share) } else { 0.0 };
+        (*b_type, w)
+    }).collect()
+}
+
+fn add_bounty(game_state: &mut GameState, t: f32) {
+    let zones = zones(&game_state.map);
+    let weights = type_weights(game_state, &zones, t);
+    let rng = &mut game_state.rng;
+    let b_type = match weighted_choice(rng, &weights) {
+        Some(b_type) => *b_type,
+        None => return,
+    };
+    let zone_weights: Vec<f32> = zones.iter().map(|z| if z.allows(b_type) { z.weight } else { 0.0 }).collect();
+    let z_id = match weighted_index(rng, &zone_weights) {
+        Some(z_id) => z_id,
+        None => return,
+    };
+    // the zone could be full, don't stall the sim looking for a tile
+    for _ in 0..MAX_TILE_TRIES {
+        let pos = random_tile(rng, &zones[z_id]);
+        if !game_state.map.is_reserved(pos) && !game_state.bounties.iter().any(|b| same_tile(b.pos, pos)) {
+            game_state.bounties.push(Bounty { type_: b_type, amount: b_type.amount(&game_state.rules), pos });
+            return;
+        }
+    }
+}
+
+// Decides when and what bounties spawn. Runs every frame from step. Spawning turns on below min_bounties and off
+// once there are max_bounties, and all the knobs ramp from their start to their end value as the match goes on.
+pub fn update(game_state: &mut GameState) {
+    let director = &game_state.rules.bounty_director;
+    let t = game_state.frame as f32 / director.ramp_frames.max(1) as f32;
+    let count = game_state.bounties.len() as f32;
+    if count >= director.max_bounties.at(t).round() {
+        game_state.spawn_bounties = false;
+    }
+    if count < director.min_bounties.at(t).round() {
+        game_state.spawn_bounties = true;
+    }
+    if game_state.frame < game_state.next_bounty {
+        return;
+    }
+    game_state.next_bounty = game_state.frame + (director.spawn_interval.at(t).round() as i32).max(1);
+    if game_state.spawn_bounties {
+        add_bounty(game_state, t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use super::*;
+    use crate::ruleset::{Ramp, Ruleset};
+    use crate::shapes::Rect;
+
+    fn state(map: Map) -> GameState {
+        GameState::new(0, ChaCha20Rng::seed_from_u64(0), Ruleset::default(), map, Mode::Ffa)
+    }
+
+    fn zone(x: i32, y: i32, w: i32, h: i32) -> BountyZone {
+        BountyZone { area: Rect { x, y, w, h }, weight: 1.0, types: vec![] }
+    }
+
+    #[test]
+    fn weighted_index_skips_zero_weights() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let weights = [0.0, 1.0, 0.0, 3.0, -2.0, 0.0];
+        for _ in 0..10_000 {
+            let i = weighted_index(&mut rng, &weights).unwrap();
+            assert!(weights[i] > 0.0, "picked index {} with weight {}", i, weights[i]);
+        }
+    }
+
+    #[test]
+    fn weighted_index_none_without_positive_weights() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        assert_eq!(weighted_index(&mut rng, &[]), None);
+        assert_eq!(weighted_index(&mut rng, &[0.0, -1.0]), None);
+    }
+
+    #[test]
+    fn ramp_stays_at_its_ends() {
+        let ramp = Ramp { start: 2.0, end: 6.0 };
+        assert_eq!(ramp.at(-1.0), 2.0);
+        assert_eq!(ramp.at(0.5), 4.0);
+        assert_eq!(ramp.at(2.0), 6.0);
+    }
+
+    #[test]
+    fn spawn_interval_follows_the_ramp() {
+        let mut game_state = state(Map::default());
+        game_state.rules.bounty_director.ramp_frames = 1000;
+        game_state.rules.bounty_director.spawn_interval = Ramp { start: 100.0, end: 50.0 };
+        update(&mut game_state);
+        assert_eq!(game_state.next_bounty, 100);
+        // nothing happens before next_bounty
+        game_state.frame = 99;
+        update(&mut game_state);
+        assert_eq!(game_state.next_bounty, 100);
+        game_state.frame = 500;
+        update(&mut game_state);
+        assert_eq!(game_state.next_bounty, 575);
+        game_state.frame = 5000;
+        update(&mut game_state);
+        assert_eq!(game_state.next_bounty, 5050);
+    }
+
+    #[test]
+    fn spawns_between_min_and_max_bounties() {
+        let mut game_state = state(Map::default());
+        let director = &mut game_state.rules.bounty_director;
+        director.spawn_interval = Ramp::flat(1.0);
+        director.min_bounties = Ramp::flat(2.0);
+        director.max_bounties = Ramp::flat(3.0);
+        for frame in 0..20 {
+            game_state.frame = frame;
+            update(&mut game_state);
+        }
+        assert_eq!(game_state.bounties.len(), 3);
+        // one left is below min_bounties, so spawning turns back on
+        game_state.bounties.truncate(1);
+        for frame in 20..40 {
+            game_state.frame = frame;
+            update(&mut game_state);
+        }
+        assert_eq!(game_state.bounties.len(), 3);
+    }
+
+    #[test]
+    fn gives_up_on_a_full_map() {
+        let mut map = Map::default();
+        let a = map.area;
+        map.blocked = (a.x..=(a.x + a.w)).flat_map(|x| (a.y..=(a.y + a.h)).map(move |y| Vector2::new(x as f32, y as f32))).collect();
+        let mut game_state = state(map);
+        update(&mut game_state);
+        assert!(game_state.bounties.is_empty());
+        // and tries again on the next spawn
+        assert!(game_state.next_bounty > 0);
+    }
+
+    #[test]
+    fn zone_edges_are_inclusive() {
+        let mut map = Map::default();
+        map.bounty_zones = vec![zone(0, 0, 1, 0)];
+        let mut game_state = state(map);
+        let mut seen = vec![];
+        for _ in 0..100 {
+            game_state.bounties.clear();
+            add_bounty(&mut game_state, 0.0);
+            let pos = game_state.bounties[0].pos;
+            assert!(pos.y == 0.0 && (pos.x == 0.0 || pos.x == 1.0), "{:?} is outside the zone", pos);
+            if !seen.contains(&pos.x) {
+                seen.push(pos.x);
+            }
+        }
+        assert_eq!(seen.len(), 2);
+
+        // a zero sized zone is a single tile
+        game_state.map.bounty_zones = vec![zone(5, 5, 0, 0)];
+        game_state.bounties.clear();
+        add_bounty(&mut game_state, 0.0);
+        assert_eq!(game_state.bounties[0].pos, Vector2::new(5.0, 5.0));
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use raylib::prelude::Vector2;
+
+use crate::*;
+use crate::agent::Agent;
+use crate::map::Map;
+use crate::ruleset::Ruleset;
+use crate::sim::{game_over, step, validate_command};
+
+// Everything a policy gets to see, from the learner's point of view.
+// Per player vecs start with the learner, followed by the other players in id order.
+#[derive(Clone)]
+pub struct Observation {
+    pub frame: i32,
+    pub units: Vec<Vec<Unit>>,
+    pub bounties: Vec<Bounty>,
+    pub interceptions: Vec<Interception>,
+    pub fuel: Vec<i32>,
+    pub gold: Vec<f32>,
+    pub lumber: Vec<i32>,
+    pub intercepted: Vec<u8>,
+    pub spawn_cooldown: Vec<i32>,
+    // team id of every player, same order as the other vecs
+    pub teams: Vec<usize>,
+}
+
+impl Observation {
+    pub fn new(game_state: &GameState, p_id: usize, frame: i32) -> Observation {
+        let order: Vec<usize> = std::iter::once(p_id).chain((0..game_state.players()).filter(|p| *p != p_id)).collect();
+        Observation {
+            frame,
+            // enemies can't tell decoys apart
+            units: order.iter().map(|p| game_state.units(*p).into_iter()
+                .map(|u| Unit { decoy: u.decoy && !game_state.enemies(*p, p_id), ..u.clone() }).collect()).collect(),
+            bounties: game_state.bounties.clone(),
+            interceptions: game_state.visible_interceptions(p_id).into_iter().cloned().collect(),
+            fuel: order.iter().map(|p| game_state.fuel[*p]).collect(),
+            gold: order.iter().map(|p| game_state.gold[*p]).collect(),
+            lumber: order.iter().map(|p| game_state.lumber[*p]).collect(),
+            intercepted: order.iter().map(|p| game_state.intercepted[*p]).collect(),
+            spawn_cooldown: order.iter().map(|p| game_state.spawn_cooldown[*p]).collect(),
+            teams: order.iter().map(|p| game_state.teams[*p]).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct RewardWeights {
+    pub kill: f32,
+    pub death: f32,
+    pub delivery: f32,
+    // per unit of fuel gained or lost
+    pub fuel: f32,
+    pub win: f32,
+    pub loss: f32,
+}
+
+impl RewardWeights {
+    // Fuel is scaled so the fuel one delivery earns under rules is worth 1, or all of start_fuel when
+    // deliveries earn none.
+    pub fn for_rules(rules: &Ruleset) -> RewardWeights {
+        let fuel_scale = [rules.economy.fuel.per_delivery, rules.start_fuel as f32].into_iter().find(|s| *s > 0.0);
+        RewardWeights {
+            kill: 1.0,
+            death: -1.0,
+            delivery: 0.5,
+            fuel: fuel_scale.map(|s| 1.0 / s).unwrap_or(0.0),
+            win: 10.0,
+            loss: -10.0,
+        }
+    }
+}
+
+pub struct Step {
+    pub observation: Observation,
+    pub reward: f32,
+    pub done: bool,
+    // the winning team, None for a draw or when max_frames was reached
+    pub winner: Option<usize>,
+    // commands from the action that failed validate_command and were dropped
+    pub rejected: Vec<GameCommand>,
+}
+
+// The simulation as a step/reset environment. The learner plays p_id, every other player is played
+// by an agent built fresh on every reset so episodes are reproducible from their seed alone.
+// Teammates are built with make_opponent too.
+pub struct Env {
+    pub p_id: usize,
+    pub rules: Ruleset,
+    pub map: Map,
+    pub mode: Mode,
+    pub rewards: RewardWeights,
+    pub max_frames: i32,
+    make_opponent: Box<dyn Fn(ChaCha20Rng) -> Box<dyn Agent>>,
+    // indexed by player id, None for the learner
+    opponents: Vec<Option<Box<dyn Agent>>>,
+    game_state: GameState,
+    frame: i32,
+}
+
+impl Env {
+    pub fn new(p_id: usize, make_opponent: Box<dyn Fn(ChaCha20Rng) -> Box<dyn Agent>>, rules: Ruleset, map: Map, mode: Mode, rewards: RewardWeights, max_frames: i32) -> Env {
+        let mut env = Env {
+            p_id,
+            game_state: GameState::new(0, ChaCha20Rng::seed_from_u64(0), rules.clone(), map.clone(), mode),
+            rules,
+            map,
+            mode,
+            rewards,
+            max_frames,
+            make_opponent,
+            opponents: vec![],
+            frame: 0,
+        };
+        env.reset(0);
+        env
+    }
+
+    pub fn reset(self: &mut Self, seed: u64) -> Observation {
+        // each opponent gets its own stream so they don't all play the same
+        self.opponents = (0..self.map.players()).map(|o_id| {
+            if o_id == self.p_id {
+                None
+            } else {
+                let mut opponent_rng = ChaCha20Rng::seed_from_u64(seed);
+                opponent_rng.set_stream(1 + o_id as u64);
+                Some((self.make_opponent)(opponent_rng))
+            }
+        }).collect();
+        self.game_state = GameState::new(0, ChaCha20Rng::seed_from_u64(seed), self.rules.clone(), self.map.clone(), self.mode);
+        self.frame = 0;
+        Observation::new(&self.game_state, self.p_id, self.frame)
+    }
+
+    pub fn state(self: &Self) -> &GameState {
+        &self.game_state
+    }
+
+    // Advances one frame with the learner issuing action
+    pub fn step(self: &mut Self, action: Vec<GameCommand>) -> Step {
+        let p_id = self.p_id;
+        let (action, rejected): (Vec<GameCommand>, Vec<GameCommand>) = action.into_iter().partition(|c| validate_command(&self.game_state, p_id, c));
+        let game_state = &self.game_state;
+        let frame = self.frame;
+        let mut updates: Vec<Vec<GameCommand>> = self.opponents.iter_mut().enumerate()
+            .map(|(o_id, m_o)| m_o.as_mut().map(|o| o.decide(game_state, o_id, frame)).unwrap_or_default())
+            .collect();
+        updates[p_id] = action;
+
+        let delivered_before = self.game_state.delivered[p_id];
+        let (intercepted_before, lost_before) = (self.game_state.intercepted[p_id], self.game_state.lost[p_id]);
+        let fuel_before = self.game_state.fuel[p_id];
+
+        step(&mut self.game_state, &updates.iter().collect::<Vec<_>>(), self.frame);
+        self.frame += 1;
+
+        let kills = (self.game_state.intercepted[p_id] - intercepted_before) as f32;
+        let deaths = (self.game_state.lost[p_id] - lost_before) as i32;
+        let delivered = self.game_state.delivered[p_id] - delivered_before;
+        let mut reward = self.rewards.kill * kills +
+            self.rewards.death * deaths as f32 +
+            self.rewards.delivery * delivered as f32 +
+            self.rewards.fuel * (self.game_state.fuel[p_id] - fuel_before) as f32;
+
+        let m_over = game_over(&self.game_state);
+        let winner = m_over.flatten();
+        match winner {
+            Some(w) if w == self.game_state.teams[p_id] => reward += self.rewards.win,
+            Some(_) => reward += self.rewards.loss,
+            None => {}
+        }
+
+        Step {
+            observation: Observation::new(&self.game_state, p_id, self.frame),
+            reward,
+            done: m_over.is_some() || self.frame >= self.max_frames,
+            winner,
+            rejected,
+        }
+    }
+
+    // A finite set of commands that are legal right now, for policies with a discrete action space.
+    // Spawns and decoys are limited to the two single turn paths to each station tile.
+    pub fn legal_commands(self: &Self) -> Vec<GameCommand> {
+        let p_id = self.p_id;
+        let mut out = vec![];
+        let map = &self.game_state.map;
+        let start = *map.ship(p_id);
+        for s in map.station(p_id) {
+            for corner in [Vector2::new(s.x, start.y), Vector2::new(start.x, s.y)] {
+                let path = VecDeque::from(vec![start, corner, *s]);
+                out.push(GameCommand::Spawn(SpawnMsgCommand { player_id: p_id, path: path.clone() }));
+                out.push(GameCommand::UseItem(ItemUse::Decoy(SpawnMsgCommand { player_id: p_id, path })));
+            }
+        }
+        for x in map.area.x..=(map.area.x + map.area.w) {
+            for y in map.area.y..=(map.area.y + map.area.h) {
+                out.push(GameCommand::Intercept(InterceptCommand { pos: Vector2::new(x as f32, y as f32) }));
+                out.push(GameCommand::Grapple(GrappleCommand { pos: Vector2::new(x as f32, y as f32) }));
+            }
+        }
+        for u_id in self.game_state.units(p_id).iter().map(|u| u.id) {
+            out.push(GameCommand::Blink(BlinkCommand { u_id }));
+            out.push(GameCommand::UseItem(ItemUse::Shield { u_id }));
+        }
+        for u in Upgrade::ALL {
+            out.push(GameCommand::BuyUpgrade(u));
+        }
+        for i in Item::ALL {
+            out.push(GameCommand::BuyItem(i));
+        }
+        out.push(GameCommand::UseItem(ItemUse::FuelCanister));
+        out.push(GameCommand::UseItem(ItemUse::Reveal));
+        out.retain(|c| validate_command(&self.game_state, p_id, c));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuel_weight_follows_the_rules() {
+        let mut rules = Ruleset::default();
+        rules.economy.fuel.per_delivery = 200.0;
+        assert_eq!(RewardWeights::for_rules(&rules).fuel, 1.0 / 200.0);
+    }
+
+    #[test]
+    fn fuel_weight_is_finite_without_delivery_fuel() {
+        let mut rules = Ruleset::default();
+        rules.economy.fuel.per_delivery = 0.0;
+        assert_eq!(RewardWeights::for_rules(&rules).fuel, 1.0 / rules.start_fuel as f32);
+        rules.start_fuel = 0;
+        assert_eq!(RewardWeights::for_rules(&rules).fuel, 0.0);
+    }
+}
@@ -2,7 +2,7 @@
 extern crate serde_derive;
 
 use std::{collections::{HashMap, HashSet, VecDeque}, hash::Hash};
-use constants::{BLINK_COOLDOWN, MESSAGE_SIZE, MESSAGE_SPEED, MSG_FUEL, STARTING_GOLD, STARTING_LUMBER, START_FUEL};
+use constants::MESSAGE_SIZE;
 use raylib::prelude::{Vector2, Color, rcolor};
 use rand_chacha::ChaCha20Rng;
 
@@ -11,7 +11,17 @@ use serde::{Deserialize, Serialize};
 // TODO enable with_serde feature on raylib then we don't need serde_nested or serde remote
 use serde_nested_with::serde_nested;
 use shapes::*;
+use ruleset::{ItemRule, Ruleset, Stat, StatModifier};
+use map::{Map, ZoneKind};
 pub mod constants;
+pub mod ruleset;
+pub mod map;
+pub mod sim;
+pub mod agent;
+pub mod ai;
+pub mod env;
+pub mod route;
+pub mod director;
 
 pub struct SeqState {
     expected_seq: i32,
@@ -59,11 +69,15 @@ impl SeqState {
 
 #[derive(Clone, Copy, Ord, PartialOrd, PartialEq, Eq, Hash)]
 pub enum Selection {
+    // Unit::id of one of the local player's units
     Unit(usize),
     Station,
     Ship,
 }
 
+// one per digit key
+pub static CONTROL_GROUPS: usize = 10;
+
 #[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq, Hash)]
 pub enum SubSelection {
     Unit,
@@ -71,11 +85,45 @@ pub enum SubSelection {
     Ship
 }
 
+// What the station's shop sells
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ShopItem {
     Item(Item),
     Upgrade(Upgrade)
 }
 
+impl ShopItem {
+    // In the order the shop lists them
+    pub fn all() -> Vec<ShopItem> {
+        Upgrade::ALL.iter().map(|u| ShopItem::Upgrade(*u)).chain(Item::ALL.iter().map(|i| ShopItem::Item(*i))).collect()
+    }
+
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            ShopItem::Upgrade(Upgrade::InterceptSpeed) => "Intercept speed",
+            ShopItem::Upgrade(Upgrade::InterceptRange) => "Intercept range",
+            ShopItem::Item(Item::Decoy) => "Decoy",
+            ShopItem::Item(Item::FuelCanister) => "Fuel canister",
+            ShopItem::Item(Item::Shield) => "Shield",
+            ShopItem::Item(Item::Reveal) => "Reveal",
+        }
+    }
+
+    pub fn cost(self: &Self, rules: &Ruleset) -> f32 {
+        match self {
+            ShopItem::Upgrade(u) => u.cost(rules),
+            ShopItem::Item(i) => i.cost(rules),
+        }
+    }
+
+    pub fn buy_command(self: &Self) -> GameCommand {
+        match self {
+            ShopItem::Upgrade(u) => GameCommand::BuyUpgrade(*u),
+            ShopItem::Item(i) => GameCommand::BuyItem(*i),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Upgrade {
     InterceptSpeed,
@@ -83,23 +131,74 @@ pub enum Upgrade {
 }
 
 impl Upgrade {
-    pub fn cost(self: &Self) -> f32 {
+    // In the order their modifiers are applied
+    pub const ALL: [Upgrade; 2] = [Upgrade::InterceptSpeed, Upgrade::InterceptRange];
+
+    pub fn cost(self: &Self, rules: &Ruleset) -> f32 {
         match self {
-            Upgrade::InterceptSpeed => 100f32,
-            Upgrade::InterceptRange => 200f32,
+            Upgrade::InterceptSpeed => rules.upgrade_cost.intercept_speed,
+            Upgrade::InterceptRange => rules.upgrade_cost.intercept_range,
+        }
+    }
+
+    pub fn modifiers<'a>(self: &Self, rules: &'a Ruleset) -> &'a Vec<StatModifier> {
+        match self {
+            Upgrade::InterceptSpeed => &rules.upgrade_modifiers.intercept_speed,
+            Upgrade::InterceptRange => &rules.upgrade_modifiers.intercept_range,
         }
     }
 }
 
+// Bought into a stock with BuyItem, spent with UseItem
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Item {
-    None
+    // a message that looks real to enemies but delivers nothing
+    Decoy,
+    FuelCanister,
+    // the unit survives interceptions for a while
+    Shield,
+    // enemy interceptions are visible before they go off, only does something with Ruleset::hidden_interceptions
+    Reveal,
 }
 
 impl Item {
-    pub fn cost(self: &Self) -> f32 {
+    pub const ALL: [Item; 4] = [Item::Decoy, Item::FuelCanister, Item::Shield, Item::Reveal];
+
+    fn rule<'a>(self: &Self, rules: &'a Ruleset) -> &'a ItemRule {
         match self {
-            Item::None => 0f32
+            Item::Decoy => &rules.items.decoy,
+            Item::FuelCanister => &rules.items.fuel_canister,
+            Item::Shield => &rules.items.shield,
+            Item::Reveal => &rules.items.reveal,
+        }
+    }
+
+    pub fn cost(self: &Self, rules: &Ruleset) -> f32 {
+        self.rule(rules).cost
+    }
+
+    // most a player can hold at once
+    pub fn max_stock(self: &Self, rules: &Ruleset) -> i16 {
+        self.rule(rules).max_stock
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ItemUse {
+    // path follows the same rules as a Spawn, but costs no lumber and ignores the spawn cooldown
+    Decoy(SpawnMsgCommand),
+    FuelCanister,
+    Shield { u_id: usize },
+    Reveal,
+}
+
+impl ItemUse {
+    pub fn item(self: &Self) -> Item {
+        match self {
+            ItemUse::Decoy(_) => Item::Decoy,
+            ItemUse::FuelCanister => Item::FuelCanister,
+            ItemUse::Shield { .. } => Item::Shield,
+            ItemUse::Reveal => Item::Reveal,
         }
     }
 }
@@ -113,6 +212,8 @@ pub enum BountyEnum {
 }
 
 impl BountyEnum {
+    pub const ALL: [BountyEnum; 4] = [BountyEnum::Gold, BountyEnum::Fuel, BountyEnum::Lumber, BountyEnum::Blink];
+
     pub fn min(self) -> i32 {
         match self {
             BountyEnum::Gold => 4,
@@ -122,12 +223,12 @@ impl BountyEnum {
         }
     }
 
-    pub fn amount(self) -> i32 {
+    pub fn amount(self, rules: &Ruleset) -> i32 {
         match self {
-            BountyEnum::Gold => 50,
-            BountyEnum::Fuel => MSG_FUEL * 3,
-            BountyEnum::Lumber => 20,
-            BountyEnum::Blink => 1
+            BountyEnum::Gold => rules.bounty_amount.gold,
+            BountyEnum::Fuel => rules.bounty_amount.fuel,
+            BountyEnum::Lumber => rules.bounty_amount.lumber,
+            BountyEnum::Blink => rules.bounty_amount.blink
         }
     }
 
@@ -149,52 +250,138 @@ pub struct Bounty {
     pub pos: Vector2,
 }
 
+// How players are split into teams. Teammates can't intercept each other and win together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    // everyone for themselves
+    Ffa,
+    // even player ids against odd ones, 2v2 on a 4 player map
+    Teams,
+}
+
+impl Mode {
+    pub fn from_arg(s: &str) -> Option<Mode> {
+        match s {
+            "ffa" => Some(Mode::Ffa),
+            "teams" => Some(Mode::Teams),
+            _ => None,
+        }
+    }
+
+    // Team id of every player
+    pub fn teams(self, players: usize) -> Vec<usize> {
+        match self {
+            Mode::Ffa => (0..players).collect(),
+            Mode::Teams => (0..players).map(|p| p % 2).collect(),
+        }
+    }
+}
+
+// A grapple hook on its way to target, or back to the ship once it has turned around
+#[derive(Copy, Clone)]
+pub struct Grapple {
+    pub player_id: usize,
+    pub pos: Vector2,
+    pub target: Vector2,
+    pub returning: bool,
+    // picked up at target, credited when the hook is back at the ship
+    pub bounty: Option<Bounty>,
+}
+
+// delay, expiry and radius are the owner's stats when it was placed, later upgrades don't change it
 #[derive(Copy, Clone)]
 pub struct Interception {
     pub start_frame: i32,
     pub pos: Vector2,
     pub player_id: usize,
+    pub delay: i32,
+    pub expiry: i32,
+    pub radius: i32,
+}
+
+impl Interception {
+    // Whether tile p is hit
+    pub fn covers(self: &Self, p: Vector2) -> bool {
+        (p.x - self.pos.x).abs() <= self.radius as f32 && (p.y - self.pos.y).abs() <= self.radius as f32
+    }
+
+    pub fn tiles(self: &Self) -> Vec<Vector2> {
+        let mut out = vec![];
+        for dx in -self.radius..=self.radius {
+            for dy in -self.radius..=self.radius {
+                out.push(self.pos + Vector2::new(dx as f32, dy as f32));
+            }
+        }
+        out
+    }
 }
 
 #[derive(Clone)]
 pub struct GameState {
+    // the local player. Only the client's UI looks at this, the simulation is the same on every peer
     pub p_id: usize,
-    pub my_units: Vec<Unit>,
-    pub other_units: Vec<Unit>,
     // TODO this should actually be (bool, bool, HashSet<unit_id>)
     // currently it is possible to select mutliple ships/stations
     pub selection: HashSet<Selection>,
     pub sub_selection: Option<SubSelection>,
-    pub fuel: [i32; 2],
-    pub intercepted: [u8; 2],
-    pub gold: [f32; 2],
-    pub lumber: [i32; 2],
-    pub upgrades: [HashSet<Upgrade>; 2],
-    pub items: [HashMap<Item, i16>; 2],
-    pub spawn_cooldown: [i32; 2],
+    // control groups, indexed by the digit they are bound to
+    pub groups: Vec<HashSet<Selection>>,
+    // per player arrays are indexed by player id
+    // every player's units in the order they were spawned
+    pub units: Vec<Vec<Unit>>,
+    pub fuel: Vec<i32>,
+    // enemy units this player intercepted
+    pub intercepted: Vec<u8>,
+    // units this player lost to interceptions
+    pub lost: Vec<u8>,
+    // messages this player got to their station, decoys don't count
+    pub delivered: Vec<u32>,
+    pub gold: Vec<f32>,
+    pub lumber: Vec<i32>,
+    pub upgrades: Vec<HashSet<Upgrade>>,
+    pub items: Vec<HashMap<Item, i16>>,
+    pub spawn_cooldown: Vec<i32>,
+    // frames left of Item::Reveal
+    pub reveal: Vec<i32>,
+    pub grapple_cooldown: Vec<i32>,
+    // team id of every player
+    pub teams: Vec<usize>,
     pub bounties: Vec<Bounty>,
     pub last_bounty: HashMap<BountyEnum, i32>,
     pub spawn_bounties: bool,
+    // frame the director spawns the next bounty at
+    pub next_bounty: i32,
     pub interceptions: Vec<Interception>,
+    pub grapples: Vec<Grapple>,
+    // Unit::id of the next unit spawned
+    pub next_unit_id: usize,
+    // the frame step was last called with
+    pub frame: i32,
     pub rng: ChaCha20Rng,
+    pub rules: Ruleset,
+    pub map: Map,
 }
 
 impl GameState {
-    pub fn new(p_id: usize, rng: ChaCha20Rng) -> GameState {
+    pub fn new(p_id: usize, rng: ChaCha20Rng, rules: Ruleset, map: Map, mode: Mode) -> GameState {
+        let players = map.players();
         GameState {
             p_id,
-            my_units: vec![],
-            other_units: vec![],
             selection: HashSet::from([Selection::Ship]),
             sub_selection: Some(SubSelection::Ship),
-            fuel: [START_FUEL; 2],
-            intercepted: [0; 2],
-            gold: [STARTING_GOLD; 2],
-            lumber: [STARTING_LUMBER; 2],
-            upgrades: [HashSet::new(), HashSet::new()],
-            items: [HashMap::new(), HashMap::new()],
+            groups: vec![HashSet::new(); CONTROL_GROUPS],
+            units: vec![vec![]; players],
+            fuel: vec![rules.start_fuel; players],
+            intercepted: vec![0; players],
+            lost: vec![0; players],
+            delivered: vec![0; players],
+            gold: vec![rules.starting_gold; players],
+            lumber: vec![rules.starting_lumber; players],
+            upgrades: vec![HashSet::new(); players],
+            items: vec![HashMap::new(); players],
             bounties: vec![],
             spawn_bounties: true,
+            next_bounty: 0,
             last_bounty: HashMap::from([
                 (BountyEnum::Blink, 0),
                 (BountyEnum::Fuel, 0),
@@ -202,10 +389,72 @@ impl GameState {
                 (BountyEnum::Lumber, 0)
             ]),
             interceptions: vec![],
-            spawn_cooldown: [0; 2],
+            grapples: vec![],
+            next_unit_id: 0,
+            spawn_cooldown: vec![0; players],
+            reveal: vec![0; players],
+            grapple_cooldown: vec![0; players],
+            teams: mode.teams(players),
+            frame: 0,
             rng,
+            rules,
+            map,
         }
     }
+
+    pub fn players(self: &Self) -> usize {
+        self.teams.len()
+    }
+
+    pub fn enemies(self: &Self, a: usize, b: usize) -> bool {
+        self.teams[a] != self.teams[b]
+    }
+
+    // Units of player p_id in spawn order
+    pub fn units(self: &Self, p_id: usize) -> &[Unit] {
+        &self.units[p_id]
+    }
+
+    // Everyone's units, by player id then spawn order
+    pub fn all_units(self: &Self) -> impl Iterator<Item = &Unit> {
+        self.units.iter().flatten()
+    }
+
+    // Player p_id's unit with Unit::id u_id, if it is still alive. Commands name units this way.
+    pub fn unit(self: &Self, p_id: usize, u_id: usize) -> Option<&Unit> {
+        self.units[p_id].iter().find(|u| u.id == u_id)
+    }
+
+    pub fn unit_mut(self: &mut Self, p_id: usize, u_id: usize) -> Option<&mut Unit> {
+        self.units[p_id].iter_mut().find(|u| u.id == u_id)
+    }
+
+    pub fn item_count(self: &Self, p_id: usize, item: Item) -> i16 {
+        *self.items[p_id].get(&item).unwrap_or(&0)
+    }
+
+    // Interceptions p_id can see. With Ruleset::hidden_interceptions enemy ones only show up once they go off,
+    // unless p_id has Item::Reveal running
+    pub fn visible_interceptions(self: &Self, p_id: usize) -> Vec<&Interception> {
+        self.interceptions.iter()
+            .filter(|i| !self.rules.hidden_interceptions || !self.enemies(i.player_id, p_id) || self.reveal[p_id] > 0 ||
+                self.frame - i.start_frame >= i.delay)
+            .collect()
+    }
+
+    // stat for player p_id after their upgrades
+    pub fn stat(self: &Self, p_id: usize, stat: Stat) -> i32 {
+        let (add, mult) = Upgrade::ALL.iter().filter(|u| self.upgrades[p_id].contains(u))
+            .flat_map(|u| u.modifiers(&self.rules).iter())
+            .filter(|m| m.stat == stat)
+            .fold((0.0, 1.0), |(add, mult), m| (add + m.add, mult * m.mult));
+        ((self.rules.base_stat(stat) as f32 + add) * mult).round().max(0.0) as i32
+    }
+
+    // Units p_id is allowed to intercept
+    pub fn enemy_units(self: &Self, p_id: usize) -> Vec<&Unit> {
+        self.all_units().filter(|u| self.enemies(u.player_id, p_id)).collect()
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -215,7 +464,8 @@ struct Vector2Def {
     pub y: f32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+// How a unit gets to the start of a TargetCommand path
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum Target {
     Move,
     Blink,
@@ -224,6 +474,8 @@ pub enum Target {
 #[serde_nested]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Unit {
+    // handed out in spawn order and never reused, the same on every peer
+    pub id: usize,
     pub dead: bool,
     pub player_id: usize,
     #[serde(with = "Vector2Def")]
@@ -233,6 +485,12 @@ pub struct Unit {
     pub blinking: Option<bool>,
     pub blink_cooldown: i32,
     pub carrying_bounty: HashMap<BountyEnum, i32>,
+    // in the order they were first entered
+    pub zones_crossed: Vec<ZoneKind>,
+    // only its owner and their team know
+    pub decoy: bool,
+    // frames left of Item::Shield
+    pub shield: i32,
 }
 
 pub fn unit_rect(pos: &Vector2, size: &Vector2) -> Rect<i32> {
@@ -254,12 +512,12 @@ impl Unit {
         MESSAGE_SIZE
     }
 
-    pub fn speed(self: &Self) -> f32 {
-        MESSAGE_SPEED
+    pub fn speed(self: &Self, rules: &Ruleset, map: &Map) -> f32 {
+        rules.message_speed * map.zone_at(self.pos).map(|z| rules.zones.get(z).speed_mult).unwrap_or(1.0)
     }
 
-    pub fn cooldown(self: &Self) -> i32 {
-        BLINK_COOLDOWN
+    pub fn cooldown(self: &Self, rules: &Ruleset) -> i32 {
+        rules.blink_cooldown
     }
 
     pub fn p0_colors(self: &Self) -> Color {
@@ -282,6 +540,13 @@ pub struct InterceptCommand {
     pub pos: Vector2,
 }
 
+// Shoots a hook from the ship at the tile pos
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GrappleCommand {
+    #[serde(with = "Vector2Def")]
+    pub pos: Vector2,
+}
+
 #[serde_nested]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SpawnMsgCommand {
@@ -290,13 +555,27 @@ pub struct SpawnMsgCommand {
     pub path: VecDeque<Vector2>,
 }
 
+// Sends the unit with Unit::id u_id along path, which has to end at the station. With Target::Move path starts somewhere on what
+// is left of the unit's old path and the unit follows that until it gets there.
+#[serde_nested]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TargetCommand {
+    pub u_id: usize,
+    pub target: Target,
+    #[serde_nested(sub = "Vector2", serde(with = "Vector2Def"))]
+    pub path: VecDeque<Vector2>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum GameCommand {
     Blink(BlinkCommand),
     Spawn(SpawnMsgCommand),
+    Target(TargetCommand),
     Intercept(InterceptCommand),
+    Grapple(GrappleCommand),
     BuyUpgrade(Upgrade),
     BuyItem(Item),
+    UseItem(ItemUse),
 }
 
 #[derive(Deserialize, Serialize)]
@@ -319,7 +598,51 @@ pub struct ServerPkt {
 #[derive(Deserialize, Serialize)]
 pub enum ServerEnum {
     Welcome { handshake_start_time: f64, player_id: usize },
-    Start { rng_seed: [u8; 32] },
-    UpdateOtherTarget { updates: VecDeque<(i32, Vec<GameCommand>)>, frame: i32, frame_ack: i32, frame_delay: u8 },
+    Start { rng_seed: [u8; 32], ruleset: Ruleset, ruleset_hash: u32, map_id: String, map_hash: u32, mode: Mode },
+    // commands from player_id
+    UpdateOtherTarget { player_id: usize, updates: VecDeque<(i32, Vec<GameCommand>)>, frame: i32, frame_ack: i32, frame_delay: u8 },
     PeerDisconnect,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use super::*;
+
+    fn state() -> GameState {
+        GameState::new(0, ChaCha20Rng::seed_from_u64(0), Ruleset::default(), Map::default(), Mode::Ffa)
+    }
+
+    #[test]
+    fn stats_start_at_the_base() {
+        let game_state = state();
+        assert_eq!(game_state.stat(0, Stat::InterceptDelay), game_state.rules.intercept_delay);
+        assert_eq!(game_state.stat(0, Stat::InterceptRadius), game_state.rules.intercept_radius);
+    }
+
+    #[test]
+    fn upgrades_only_change_their_owners_stats() {
+        let mut game_state = state();
+        game_state.upgrades[0] = HashSet::from(Upgrade::ALL);
+        // half the delay and a 3x3 hit
+        assert_eq!(game_state.stat(0, Stat::InterceptDelay), game_state.rules.intercept_delay / 2);
+        assert_eq!(game_state.stat(0, Stat::InterceptRadius), game_state.rules.intercept_radius + 1);
+        assert_eq!(game_state.stat(0, Stat::InterceptExpiry), game_state.rules.intercept_expiry);
+        assert_eq!(game_state.stat(1, Stat::InterceptDelay), game_state.rules.intercept_delay);
+    }
+
+    #[test]
+    fn stats_never_go_negative() {
+        let mut game_state = state();
+        game_state.rules.upgrade_modifiers.intercept_range = vec![StatModifier { stat: Stat::InterceptRadius, add: -5.0, mult: 1.0 }];
+        game_state.upgrades[0].insert(Upgrade::InterceptRange);
+        assert_eq!(game_state.stat(0, Stat::InterceptRadius), 0);
+    }
+
+    #[test]
+    fn interception_covers_its_radius() {
+        let i = Interception { start_frame: 0, pos: Vector2::new(2.0, 2.0), player_id: 0, delay: 0, expiry: 0, radius: 1 };
+        assert!(i.covers(Vector2::new(3.0, 1.0)));
+        assert!(!i.covers(Vector2::new(4.0, 2.0)));
+        assert_eq!(i.tiles().len(), 9);
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use raylib::prelude::Vector2;
+use serde::{Deserialize, Serialize};
+
+use crate::BountyEnum;
+use crate::constants::AreaEnum;
+use crate::shapes::Rect;
+
+pub static DEFAULT_MAP_ID: &str = "default";
+static MAP_DIR: &str = "maps";
+// the renderer has colors and shadows for this many ships
+pub static MAX_PLAYERS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneKind {
+    River,
+    Nebula,
+    FastLane,
+}
+
+// Terrain that changes messages passing through it, see Ruleset::zones for what each kind does.
+// Both edges of area are part of the zone, like Map::area.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zone {
+    pub kind: ZoneKind,
+    pub area: Rect<i32>,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+// Where bounties spawn. Written like a Rect and inclusive of its far edges like the area and zones, so w 0
+// is a single column. Weight and types are optional.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BountyZone {
+    #[serde(flatten)]
+    pub area: Rect<i32>,
+    // relative to the other zones
+    #[serde(default = "one")]
+    pub weight: f32,
+    // types that can spawn here, empty means all of them
+    #[serde(default)]
+    pub types: Vec<BountyEnum>,
+}
+
+impl BountyZone {
+    pub fn allows(self: &Self, b_type: BountyEnum) -> bool {
+        self.types.is_empty() || self.types.contains(&b_type)
+    }
+}
+
+// What maps/<id>.json contains. Tiles are [x, y].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapFile {
+    // both edges are playable
+    pub area: Rect<i32>,
+    // one per player, this is how many players the map is for
+    pub ships: Vec<[i32; 2]>,
+    // one list per player
+    pub stations: Vec<Vec<[i32; 2]>>,
+    #[serde(default)]
+    pub blocked: Vec<[i32; 2]>,
+    // bounties only spawn inside these. Empty means anywhere in area
+    #[serde(default)]
+    pub bounty_zones: Vec<BountyZone>,
+    // where two overlap the first one listed wins
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+// Board layout. The server picks the id, both clients load it from disk and check the hash.
+#[derive(Debug, Clone)]
+pub struct Map {
+    pub id: String,
+    pub area: Rect<i32>,
+    pub ships: Vec<Vector2>,
+    pub stations: Vec<Vec<Vector2>>,
+    pub blocked: Vec<Vector2>,
+    pub bounty_zones: Vec<BountyZone>,
+    pub zones: Vec<Zone>,
+    pub hash: u32,
+}
+
+fn tile(t: &[i32; 2]) -> Vector2 {
+    Vector2::new(t[0] as f32, t[1] as f32)
+}
+
+impl MapFile {
+    fn validate(self: &Self) -> Result<(), String> {
+        if self.area.w <= 0 || self.area.h <= 0 {
+            return Err("area must have a positive size".to_string());
+        }
+        if self.ships.len() < 2 || self.ships.len() > MAX_PLAYERS || self.stations.len() != self.ships.len() {
+            return Err(format!("expected a ship and a station list for each of 2 to {} players", MAX_PLAYERS));
+        }
+        if self.stations.iter().any(|s| s.is_empty()) {
+            return Err("every player needs at least one station tile".to_string());
+        }
+
+        let mut seen = HashSet::new();
+        let tiles = self.ships.iter()
+            .chain(self.stations.iter().flatten())
+            .chain(self.blocked.iter());
+        for t in tiles {
+            if !self.area.contains_point(&tile(t)) {
+                return Err(format!("tile {:?} is outside the area", t));
+            }
+            if !seen.insert(*t) {
+                return Err(format!("tile {:?} is used more than once", t));
+            }
+        }
+
+        for z in self.bounty_zones.iter() {
+            if z.area.w < 0 || z.area.h < 0 || !self.area.contains(&z.area) {
+                return Err(format!("bounty zone {:?} must be inside the area", z.area));
+            }
+            if z.weight < 0.0 {
+                return Err(format!("bounty zone {:?} has a negative weight", z.area));
+            }
+        }
+        for z in self.zones.iter() {
+            if z.area.w < 0 || z.area.h < 0 || !self.area.contains(&z.area) {
+                return Err(format!("zone {:?} must be inside the area", z));
+            }
+        }
+        let zones: Vec<Rect<i32>> = if self.bounty_zones.is_empty() {
+            vec![self.area]
+        } else {
+            self.bounty_zones.iter().filter(|z| z.weight > 0.0).map(|z| z.area).collect()
+        };
+        let has_free_tile = zones.iter().any(|z|
+            (z.x..=(z.x + z.w)).any(|x| (z.y..=(z.y + z.h)).any(|y| !seen.contains(&[x, y]))));
+        if !has_free_tile {
+            return Err("bounty zones have no free tile".to_string());
+        }
+        Ok(())
+    }
+}
+
+// What the board looked like before maps were loaded from files
+impl Default for Map {
+    fn default() -> Map {
+        let file = MapFile {
+            area: Rect { x: -12, y: -12, w: 24, h: 24 },
+            ships: vec![[-12, 11], [-11, 12]],
+            stations: vec![
+                vec![[11, -12], [10, -12], [9, -12], [8, -12]],
+                vec![[12, -11], [12, -10], [12, -9], [12, -8]],
+            ],
+            blocked: vec![],
+            bounty_zones: vec![],
+            zones: vec![],
+        };
+        Map::from_file(DEFAULT_MAP_ID, file).unwrap()
+    }
+}
+
+impl Map {
+    pub fn from_file(id: &str, file: MapFile) -> Result<Map, String> {
+        file.validate().map_err(|e| format!("map {}: {}", id, e))?;
+        let hash = crc32fast::hash(&rmp_serde::encode::to_vec(&file).unwrap());
+        Ok(Map {
+            id: id.to_string(),
+            area: file.area,
+            ships: file.ships.iter().map(tile).collect(),
+            stations: file.stations.iter().map(|s| s.iter().map(tile).collect()).collect(),
+            blocked: file.blocked.iter().map(tile).collect(),
+            bounty_zones: file.bounty_zones,
+            zones: file.zones,
+            hash,
+        })
+    }
+
+    // Loads maps/<id>.json. The default map doesn't need a file.
+    pub fn load(id: &str) -> Result<Map, String> {
+        // the id comes from the server, don't let it point outside MAP_DIR
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("invalid map id {:?}", id));
+        }
+        let path = format!("{}/{}.json", MAP_DIR, id);
+        if id == DEFAULT_MAP_ID && !std::path::Path::new(&path).exists() {
+            return Ok(Map::default());
+        }
+        let s = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let file: MapFile = serde_json::from_str(&s).map_err(|e| format!("{}: {}", path, e))?;
+        Map::from_file(id, file)
+    }
+
+    pub fn players(self: &Self) -> usize {
+        self.ships.len()
+    }
+
+    pub fn ship(self: &Self, p_id: usize) -> &Vector2 {
+        &self.ships[p_id]
+    }
+
+    pub fn station(self: &Self, p_id: usize) -> &[Vector2] {
+        &self.stations[p_id]
+    }
+
+    pub fn area_at(self: &Self, p: Vector2) -> Option<AreaEnum> {
+        let p = Vector2::new(p.x.round(), p.y.round());
+        if let Some(p_id) = self.ships.iter().position(|s| *s == p) {
+            Some(AreaEnum::Spawn(p_id))
+        } else if let Some(p_id) = self.stations.iter().position(|s| s.contains(&p)) {
+            Some(AreaEnum::Station(p_id))
+        } else if self.blocked.contains(&p) {
+            Some(AreaEnum::Blocked)
+        } else {
+            None
+        }
+    }
+
+    pub fn zone_at(self: &Self, p: Vector2) -> Option<ZoneKind> {
+        let p = Vector2::new(p.x.round(), p.y.round());
+        self.zones.iter().find(|z| z.area.contains_point(&p)).map(|z| z.kind)
+    }
+
+    // Tiles a bounty can't spawn on
+    pub fn is_reserved(self: &Self, p: Vector2) -> bool {
+        self.area_at(p).is_some()
+    }
+
+    // Whether the segment a-b passes through a blocked tile. Tiles are 1x1 squares centered on their position.
+    pub fn crosses_blocked(self: &Self, a: &Vector2, b: &Vector2) -> bool {
+        self.blocked.iter().any(|t| Rect { x: t.x - 0.5, y: t.y - 0.5, w: 1.0, h: 1.0 }.collide_line(a, b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 5x5 tiles, ships in two corners and a station in each of the others
+    fn file() -> MapFile {
+        MapFile {
+            area: Rect { x: 0, y: 0, w: 4, h: 4 },
+            ships: vec![[0, 0], [4, 4]],
+            stations: vec![vec![[4, 0]], vec![[0, 4]]],
+            blocked: vec![],
+            bounty_zones: vec![],
+            zones: vec![],
+        }
+    }
+
+    fn bounty_zone(x: i32, y: i32, w: i32, h: i32, weight: f32) -> BountyZone {
+        BountyZone { area: Rect { x, y, w, h }, weight, types: vec![] }
+    }
+
+    fn rejected(file: MapFile, error: &str) {
+        let e = file.validate().unwrap_err();
+        assert!(e.contains(error), "{:?} doesn't mention {:?}", e, error);
+    }
+
+    #[test]
+    fn accepts_a_valid_map() {
+        let mut f = file();
+        f.blocked = vec![[2, 2]];
+        f.bounty_zones = vec![bounty_zone(1, 1, 2, 2, 1.0)];
+        f.zones = vec![Zone { kind: ZoneKind::River, area: Rect { x: 0, y: 2, w: 4, h: 0 } }];
+        assert!(f.validate().is_ok());
+        assert_eq!(Map::from_file("test", f).unwrap().players(), 2);
+    }
+
+    #[test]
+    fn rejects_an_empty_area() {
+        let mut f = file();
+        f.area.w = 0;
+        rejected(f, "positive size");
+    }
+
+    #[test]
+    fn rejects_a_wrong_player_count() {
+        let mut f = file();
+        f.ships.truncate(1);
+        f.stations.truncate(1);
+        rejected(f, "2 to 4 players");
+        let mut f = file();
+        f.stations.push(vec![[2, 0]]);
+        rejected(f, "2 to 4 players");
+    }
+
+    #[test]
+    fn rejects_an_empty_station() {
+        let mut f = file();
+        f.stations[1].clear();
+        rejected(f, "at least one station tile");
+    }
+
+    #[test]
+    fn rejects_a_tile_outside_the_area() {
+        let mut f = file();
+        f.blocked = vec![[5, 2]];
+        rejected(f, "outside the area");
+    }
+
+    #[test]
+    fn rejects_a_tile_used_twice() {
+        let mut f = file();
+        f.blocked = vec![[4, 0]];
+        rejected(f, "more than once");
+    }
+
+    #[test]
+    fn rejects_a_bounty_zone_outside_the_area() {
+        let mut f = file();
+        f.bounty_zones = vec![bounty_zone(2, 2, 3, 0, 1.0)];
+        rejected(f, "bounty zone");
+        let mut f = file();
+        f.bounty_zones = vec![bounty_zone(2, 2, -1, 0, 1.0)];
+        rejected(f, "bounty zone");
+    }
+
+    #[test]
+    fn rejects_a_negative_weight() {
+        let mut f = file();
+        f.bounty_zones = vec![bounty_zone(1, 1, 2, 2, -1.0)];
+        rejected(f, "negative weight");
+    }
+
+    #[test]
+    fn rejects_a_terrain_zone_outside_the_area() {
+        let mut f = file();
+        f.zones = vec![Zone { kind: ZoneKind::Nebula, area: Rect { x: -1, y: 0, w: 2, h: 2 } }];
+        rejected(f, "must be inside the area");
+    }
+
+    #[test]
+    fn rejects_bounty_zones_without_a_free_tile() {
+        // the only zone that can spawn is the ship's tile, the free one has no weight
+        let mut f = file();
+        f.bounty_zones = vec![bounty_zone(0, 0, 0, 0, 1.0), bounty_zone(2, 2, 0, 0, 0.0)];
+        rejected(f, "no free tile");
+    }
+
+    #[test]
+    fn load_rejects_ids_outside_the_map_dir() {
+        assert!(Map::load("../ruleset").unwrap_err().starts_with("invalid map id"));
+        assert!(Map::load("").is_err());
+        assert_eq!(Map::load(DEFAULT_MAP_ID).unwrap().id, DEFAULT_MAP_ID);
+    }
+
+    #[test]
+    fn segment_through_a_blocked_tile_is_blocked() {
+        let mut f = file();
+        f.blocked = vec![[2, 2]];
+        let map = Map::from_file("test", f).unwrap();
+        assert!(map.crosses_blocked(&Vector2::new(0.0, 2.0), &Vector2::new(4.0, 2.0)));
+        assert!(map.crosses_blocked(&Vector2::new(2.0, 4.0), &Vector2::new(2.0, 2.0)));
+        assert!(map.area_at(Vector2::new(2.2, 1.8)) == Some(AreaEnum::Blocked));
+    }
+
+    #[test]
+    fn segment_next_to_a_blocked_tile_is_not_blocked() {
+        let mut f = file();
+        f.blocked = vec![[2, 2]];
+        let map = Map::from_file("test", f).unwrap();
+        // the row above and a segment stopping on the tile before it
+        assert!(!map.crosses_blocked(&Vector2::new(0.0, 1.0), &Vector2::new(4.0, 1.0)));
+        assert!(!map.crosses_blocked(&Vector2::new(0.0, 2.0), &Vector2::new(1.0, 2.0)));
+        assert!(map.area_at(Vector2::new(1.0, 2.0)).is_none());
+    }
+
+    #[test]
+    fn first_listed_zone_wins() {
+        let mut f = file();
+        f.zones = vec![
+            Zone { kind: ZoneKind::Nebula, area: Rect { x: 1, y: 1, w: 1, h: 1 } },
+            Zone { kind: ZoneKind::River, area: Rect { x: 0, y: 2, w: 4, h: 0 } },
+        ];
+        let map = Map::from_file("test", f).unwrap();
+        assert_eq!(map.zone_at(Vector2::new(1.0, 2.0)), Some(ZoneKind::Nebula));
+        assert_eq!(map.zone_at(Vector2::new(3.4, 2.0)), Some(ZoneKind::River));
+        assert_eq!(map.zone_at(Vector2::new(3.0, 3.0)), None);
+    }
+}
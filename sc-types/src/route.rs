@@ -0,0 +1,170 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use raylib::prelude::Vector2;
+
+use crate::shapes::Rect;
+
+static DIRS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Node {
+    x: i32,
+    y: i32,
+    // index into DIRS, None before the first step
+    dir: Option<usize>,
+    // number of waypoints visited so far
+    leg: usize,
+}
+
+fn tile(v: &Vector2) -> (i32, i32) {
+    (v.x.round() as i32, v.y.round() as i32)
+}
+
+fn dir_index(d: Vector2) -> Option<usize> {
+    let sign = |v: f32| if v > 0.0 { 1 } else if v < 0.0 { -1 } else { 0 };
+    DIRS.iter().position(|dir| *dir == (sign(d.x), sign(d.y)))
+}
+
+// Axis aligned path inside area from start to the first of goals it reaches, visiting waypoints in order and never
+// stepping on an avoid tile. Minimizes turns (and so path_lumber_cost), then length.
+// start_dir is the direction the path was already heading in, so continuing straight is free.
+// Tiles in goals are only entered once every waypoint has been visited since a message is delivered
+// as soon as it touches its station.
+pub fn route(area: &Rect<i32>, start: Vector2, start_dir: Option<Vector2>, goals: &[Vector2], waypoints: &[Vector2], avoid: &[Vector2]) -> Option<VecDeque<Vector2>> {
+    let goals: Vec<(i32, i32)> = goals.iter().map(tile).collect();
+    let waypoints: Vec<(i32, i32)> = waypoints.iter().map(tile).collect();
+    let avoid: Vec<(i32, i32)> = avoid.iter().map(tile).collect();
+    let in_area = |(x, y): (i32, i32)| x >= area.x && x <= area.x + area.w && y >= area.y && y <= area.y + area.h;
+
+    let (sx, sy) = tile(&start);
+    let mut leg = 0;
+    while leg < waypoints.len() && waypoints[leg] == (sx, sy) {
+        leg += 1;
+    }
+    let start_node = Node { x: sx, y: sy, dir: start_dir.and_then(dir_index), leg };
+
+    // cost is (turns, steps)
+    let mut best: HashMap<Node, ((i32, i32), Option<Node>)> = HashMap::from([(start_node, ((0, 0), None))]);
+    let mut queue = BinaryHeap::from([Reverse(((0, 0), start_node))]);
+    let mut m_end = None;
+    while let Some(Reverse((cost, node))) = queue.pop() {
+        if best.get(&node).map(|(c, _)| *c < cost).unwrap_or(false) {
+            continue;
+        }
+        if node.leg == waypoints.len() && goals.contains(&(node.x, node.y)) {
+            m_end = Some(node);
+            break;
+        }
+        for (d, (dx, dy)) in DIRS.iter().enumerate() {
+            let next_tile = (node.x + dx, node.y + dy);
+            if !in_area(next_tile) || avoid.contains(&next_tile) {
+                continue;
+            }
+            let mut next_leg = node.leg;
+            while next_leg < waypoints.len() && waypoints[next_leg] == next_tile {
+                next_leg += 1;
+            }
+            if next_leg < waypoints.len() && goals.contains(&next_tile) {
+                continue;
+            }
+            let next = Node { x: next_tile.0, y: next_tile.1, dir: Some(d), leg: next_leg };
+            let turn = if node.dir.is_some() && node.dir != Some(d) { 1 } else { 0 };
+            let next_cost = (cost.0 + turn, cost.1 + 1);
+            if best.get(&next).map(|(c, _)| next_cost < *c).unwrap_or(true) {
+                best.insert(next, (next_cost, Some(node)));
+                queue.push(Reverse((next_cost, next)));
+            }
+        }
+    }
+
+    let mut tiles = vec![];
+    let mut m_node = m_end;
+    while let Some(node) = m_node {
+        tiles.push(node);
+        m_node = best[&node].1;
+    }
+    if tiles.is_empty() {
+        return None;
+    }
+    tiles.reverse();
+
+    // only keep the corners
+    let mut path = VecDeque::from(vec![start]);
+    for i in 1..tiles.len() {
+        if i + 1 == tiles.len() || tiles[i].dir != tiles[i + 1].dir {
+            path.push_back(Vector2::new(tiles[i].x as f32, tiles[i].y as f32));
+        }
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the default board
+    static AREA: Rect<i32> = Rect { x: -12, y: -12, w: 24, h: 24 };
+
+    fn v(x: i32, y: i32) -> Vector2 {
+        Vector2::new(x as f32, y as f32)
+    }
+
+    // every tile the path walks over, corners included
+    fn walked(path: &VecDeque<Vector2>) -> Vec<(i32, i32)> {
+        let mut tiles = vec![tile(&path[0])];
+        for (a, b) in path.iter().zip(path.iter().skip(1)) {
+            let (mut x, mut y) = tile(a);
+            let (bx, by) = tile(b);
+            while (x, y) != (bx, by) {
+                x += (bx - x).signum();
+                y += (by - y).signum();
+                tiles.push((x, y));
+            }
+        }
+        tiles
+    }
+
+    #[test]
+    fn fewest_turns_around_blocked_tile() {
+        // the board edge is right below, so going around above is the only two turn route
+        let path = route(&AREA, v(-12, -12), None, &[v(-8, -12)], &[], &[v(-10, -12)]).unwrap();
+        assert_eq!(path, VecDeque::from(vec![v(-12, -12), v(-12, -11), v(-8, -11), v(-8, -12)]));
+    }
+
+    #[test]
+    fn straight_when_heading_the_right_way() {
+        let path = route(&AREA, v(-12, -12), Some(v(1, 0)), &[v(-8, -12)], &[], &[]).unwrap();
+        assert_eq!(path, VecDeque::from(vec![v(-12, -12), v(-8, -12)]));
+    }
+
+    #[test]
+    fn fewer_turns_beat_fewer_steps() {
+        // the shortest route is 7 steps with 4 turns, this one is 11 steps with 3
+        let avoid = [v(-11, -11), v(-10, -12), v(-9, -10)];
+        let path = route(&AREA, v(-12, -12), None, &[v(-9, -12)], &[], &avoid).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(walked(&path).len(), 12);
+    }
+
+    #[test]
+    fn never_steps_on_avoid() {
+        // a wall down x = -10 with a gap at the top
+        let avoid = [v(-10, -12), v(-10, -11), v(-10, -10), v(-10, -9)];
+        let path = route(&AREA, v(-12, -12), None, &[v(-8, -12)], &[], &avoid).unwrap();
+        let tiles = walked(&path);
+        assert!(tiles.contains(&(-10, -8)));
+        assert!(avoid.iter().all(|a| !tiles.contains(&tile(a))));
+        assert_eq!(tiles.last(), Some(&(-8, -12)));
+    }
+
+    #[test]
+    fn no_route_when_avoid_blocks_the_only_way_out() {
+        let avoid = [v(-11, -12), v(-12, -11)];
+        assert!(route(&AREA, v(-12, -12), None, &[v(-8, -12)], &[], &avoid).is_none());
+    }
+
+    #[test]
+    fn no_route_to_a_goal_off_the_board() {
+        assert!(route(&AREA, v(-12, -12), None, &[v(20, 0)], &[], &[]).is_none());
+    }
+}
@@ -0,0 +1,476 @@
+use serde::{Deserialize, Serialize};
+
+use crate::BountyEnum;
+use crate::map::ZoneKind;
+
+// Fuel a player starts with. It's also the default fuel cap, so no income can take a player past a full tank
+// and a match doesn't drag on because someone stockpiled deliveries.
+static START_FUEL: i32 = 1000 * 60;
+
+// Balance values. The server loads these from a file and sends them to both clients in Start so designers
+// can iterate without recompiling. Missing fields in the file fall back to the defaults below.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ruleset {
+    pub start_fuel: i32,
+    pub starting_gold: f32,
+    pub starting_lumber: i32,
+    pub msg_cooldown: i32,
+    // turns a path can take before it costs lumber
+    pub msg_free_lumber: i32,
+    // tiles per frame
+    pub message_speed: f32,
+    pub blink_cooldown: i32,
+    pub blink_range: f32,
+    pub intercept_cost: f32,
+    pub intercept_expiry: i32,
+    pub intercept_delay: i32,
+    // tiles around the target that are also hit, 0 is just the target
+    pub intercept_radius: i32,
+    // enemies only see an interception once it goes off, or while they have Item::Reveal running.
+    // Off by default, charging interceptions are telegraphed so they can be dodged.
+    pub hidden_interceptions: bool,
+    pub grapple_cost: f32,
+    pub grapple_cooldown: i32,
+    // tiles from the ship
+    pub grapple_range: f32,
+    // tiles per frame, both ways
+    pub grapple_speed: f32,
+    pub kills_to_win: u8,
+    pub economy: Economy,
+    pub bounty_amount: BountyAmounts,
+    pub bounty_director: DirectorRules,
+    pub upgrade_cost: UpgradeCosts,
+    pub upgrade_modifiers: UpgradeModifiers,
+    pub items: ItemRules,
+    pub zones: ZoneEffects,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Fuel,
+    Gold,
+    Lumber,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 3] = [Resource::Fuel, Resource::Gold, Resource::Lumber];
+}
+
+// How a player earns (or loses) one resource, see sim::earn
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Income {
+    // gained every `every` frames, negative drains
+    pub amount: f32,
+    pub every: i32,
+    // passive income only while the player has less than this
+    pub below: Option<f32>,
+    // nothing earned raises the resource past this (income, bounties, zones, canisters)
+    pub cap: Option<f32>,
+    // per message delivered
+    pub per_delivery: f32,
+    // per enemy message intercepted
+    pub per_kill: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Economy {
+    pub fuel: Income,
+    pub gold: Income,
+    pub lumber: Income,
+}
+
+// Per player values upgrades can change, see GameState::stat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stat {
+    InterceptDelay,
+    InterceptExpiry,
+    InterceptRadius,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+// The stat becomes (base + add) * mult, where add and mult are summed and multiplied over all owned upgrades
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatModifier {
+    pub stat: Stat,
+    #[serde(default)]
+    pub add: f32,
+    #[serde(default = "one")]
+    pub mult: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BountyAmounts {
+    pub gold: i32,
+    pub fuel: i32,
+    pub lumber: i32,
+    pub blink: i32,
+}
+
+// Goes linearly from start to end over DirectorRules::ramp_frames, then stays at end
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ramp {
+    pub start: f32,
+    pub end: f32,
+}
+
+// Relative chance of each type spawning
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BountyWeights {
+    pub gold: Ramp,
+    pub fuel: Ramp,
+    pub lumber: Ramp,
+    pub blink: Ramp,
+}
+
+// See director::update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectorRules {
+    // frames until every ramp is at its end value
+    pub ramp_frames: i32,
+    // frames between spawns
+    pub spawn_interval: Ramp,
+    pub min_bounties: Ramp,
+    pub max_bounties: Ramp,
+    pub weights: BountyWeights,
+    // 0 ignores what is already on the board, 1 never picks a type when all bounties are of that type
+    pub rarity_bias: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpgradeCosts {
+    pub intercept_speed: f32,
+    pub intercept_range: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpgradeModifiers {
+    pub intercept_speed: Vec<StatModifier>,
+    pub intercept_range: Vec<StatModifier>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemRule {
+    pub cost: f32,
+    pub max_stock: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ItemRules {
+    pub decoy: ItemRule,
+    pub fuel_canister: ItemRule,
+    pub shield: ItemRule,
+    pub reveal: ItemRule,
+    // capped at start_fuel
+    pub canister_fuel: i32,
+    // frames
+    pub shield_duration: i32,
+    // frames
+    pub reveal_duration: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZoneEffect {
+    // multiplies message_speed while the message is inside
+    pub speed_mult: f32,
+    // added to the owner's fuel every frame one of their messages is inside
+    pub fuel_per_frame: i32,
+    // added to economy.fuel.per_delivery when a message that passed through is delivered
+    pub delivery_fuel: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZoneEffects {
+    pub river: ZoneEffect,
+    pub nebula: ZoneEffect,
+    pub fast_lane: ZoneEffect,
+}
+
+impl Default for Ruleset {
+    fn default() -> Ruleset {
+        Ruleset {
+            start_fuel: START_FUEL,
+            starting_gold: 300f32,
+            starting_lumber: 20,
+            msg_cooldown: 10 * 60,
+            msg_free_lumber: 3,
+            message_speed: 1.0/60.0,
+            blink_cooldown: 900,
+            blink_range: 3f32,
+            intercept_cost: 100f32,
+            intercept_expiry: 2 * 60,
+            intercept_delay: 4 * 60,
+            intercept_radius: 0,
+            hidden_interceptions: false,
+            grapple_cost: 50f32,
+            grapple_cooldown: 20 * 60,
+            grapple_range: 6f32,
+            grapple_speed: 8.0/60.0,
+            kills_to_win: 5,
+            economy: Economy::default(),
+            bounty_amount: BountyAmounts::default(),
+            bounty_director: DirectorRules::default(),
+            upgrade_cost: UpgradeCosts::default(),
+            upgrade_modifiers: UpgradeModifiers::default(),
+            items: ItemRules::default(),
+            zones: ZoneEffects::default(),
+        }
+    }
+}
+
+impl Default for BountyAmounts {
+    fn default() -> BountyAmounts {
+        BountyAmounts {
+            gold: 50,
+            // 3 deliveries worth
+            fuel: 3 * 5 * 60 * 15,
+            lumber: 20,
+            blink: 1,
+        }
+    }
+}
+
+impl Default for Income {
+    fn default() -> Income {
+        Income {
+            amount: 0.0,
+            every: 1,
+            below: None,
+            cap: None,
+            per_delivery: 0.0,
+            per_kill: 0.0,
+        }
+    }
+}
+
+impl Default for Economy {
+    fn default() -> Economy {
+        let fuel_loss = 5.0;
+        Economy {
+            // a delivery is worth 15 seconds, never more than a full tank
+            fuel: Income { amount: -fuel_loss, per_delivery: fuel_loss * 60.0 * 15.0, cap: Some(START_FUEL as f32), ..Income::default() },
+            gold: Income::default(),
+            // 1 every 5 seconds when running low
+            lumber: Income { amount: 1.0, every: 5 * 60, below: Some(5.0), ..Income::default() },
+        }
+    }
+}
+
+impl Economy {
+    pub fn get(self: &Self, resource: Resource) -> &Income {
+        match resource {
+            Resource::Fuel => &self.fuel,
+            Resource::Gold => &self.gold,
+            Resource::Lumber => &self.lumber,
+        }
+    }
+}
+
+impl Ramp {
+    pub fn flat(v: f32) -> Ramp {
+        Ramp { start: v, end: v }
+    }
+
+    // t is the fraction of ramp_frames that has passed
+    pub fn at(self: &Self, t: f32) -> f32 {
+        self.start + (self.end - self.start) * t.clamp(0.0, 1.0)
+    }
+}
+
+impl Default for BountyWeights {
+    fn default() -> BountyWeights {
+        BountyWeights {
+            gold: Ramp::flat(1.0),
+            fuel: Ramp::flat(1.0),
+            lumber: Ramp::flat(1.0),
+            blink: Ramp::flat(1.0),
+        }
+    }
+}
+
+impl BountyWeights {
+    pub fn get(self: &Self, b_type: BountyEnum) -> &Ramp {
+        match b_type {
+            BountyEnum::Gold => &self.gold,
+            BountyEnum::Fuel => &self.fuel,
+            BountyEnum::Lumber => &self.lumber,
+            BountyEnum::Blink => &self.blink,
+        }
+    }
+}
+
+impl Default for DirectorRules {
+    fn default() -> DirectorRules {
+        DirectorRules {
+            ramp_frames: 10 * 60 * 60,
+            // twice as many bounties by the 10 minute mark
+            spawn_interval: Ramp { start: 3.0 * 60.0, end: 1.5 * 60.0 },
+            min_bounties: Ramp { start: 6.0, end: 10.0 },
+            max_bounties: Ramp { start: 10.0, end: 16.0 },
+            weights: BountyWeights::default(),
+            rarity_bias: 1.0,
+        }
+    }
+}
+
+impl Default for UpgradeCosts {
+    fn default() -> UpgradeCosts {
+        UpgradeCosts {
+            intercept_speed: 100f32,
+            intercept_range: 200f32,
+        }
+    }
+}
+
+impl Default for UpgradeModifiers {
+    fn default() -> UpgradeModifiers {
+        UpgradeModifiers {
+            // goes off in half the time
+            intercept_speed: vec![StatModifier { stat: Stat::InterceptDelay, add: 0.0, mult: 0.5 }],
+            // 3x3 instead of a single tile
+            intercept_range: vec![StatModifier { stat: Stat::InterceptRadius, add: 1.0, mult: 1.0 }],
+        }
+    }
+}
+
+impl Default for ItemRules {
+    fn default() -> ItemRules {
+        ItemRules {
+            decoy: ItemRule { cost: 50f32, max_stock: 2 },
+            fuel_canister: ItemRule { cost: 150f32, max_stock: 1 },
+            shield: ItemRule { cost: 100f32, max_stock: 2 },
+            reveal: ItemRule { cost: 75f32, max_stock: 1 },
+            // 2 deliveries worth
+            canister_fuel: 2 * 5 * 60 * 15,
+            shield_duration: 5 * 60,
+            reveal_duration: 10 * 60,
+        }
+    }
+}
+
+impl Default for ZoneEffect {
+    fn default() -> ZoneEffect {
+        ZoneEffect {
+            speed_mult: 1.0,
+            fuel_per_frame: 0,
+            delivery_fuel: 0,
+        }
+    }
+}
+
+impl Default for ZoneEffects {
+    fn default() -> ZoneEffects {
+        ZoneEffects {
+            // slow to cross but worth half a delivery more
+            river: ZoneEffect { speed_mult: 0.5, delivery_fuel: 5 * 60 * 15 / 2, ..ZoneEffect::default() },
+            nebula: ZoneEffect { fuel_per_frame: -3, ..ZoneEffect::default() },
+            fast_lane: ZoneEffect { speed_mult: 2.0, ..ZoneEffect::default() },
+        }
+    }
+}
+
+impl ZoneEffects {
+    pub fn get(self: &Self, kind: ZoneKind) -> &ZoneEffect {
+        match kind {
+            ZoneKind::River => &self.river,
+            ZoneKind::Nebula => &self.nebula,
+            ZoneKind::FastLane => &self.fast_lane,
+        }
+    }
+}
+
+impl Ruleset {
+    pub fn load(path: &str) -> Result<Ruleset, String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::from_str(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Value of stat before upgrades
+    pub fn base_stat(self: &Self, stat: Stat) -> i32 {
+        match stat {
+            Stat::InterceptDelay => self.intercept_delay,
+            Stat::InterceptExpiry => self.intercept_expiry,
+            Stat::InterceptRadius => self.intercept_radius,
+        }
+    }
+
+    // Loads path if it exists, otherwise the defaults
+    pub fn load_or_default(path: &str) -> Result<Ruleset, String> {
+        if std::path::Path::new(path).exists() {
+            Ruleset::load(path)
+        } else {
+            Ok(Ruleset::default())
+        }
+    }
+
+    // Sent alongside the ruleset in Start so clients can check they are simulating the same rules
+    pub fn hash(self: &Self) -> u32 {
+        crc32fast::hash(&rmp_serde::encode::to_vec(self).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ruleset file only this test reads
+    fn write_rules(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("sc-ruleset-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn hash_matches_for_the_same_rules() {
+        assert_eq!(Ruleset::default().hash(), Ruleset::default().clone().hash());
+    }
+
+    #[test]
+    fn hash_changes_with_a_nested_rule() {
+        let mut rules = Ruleset::default();
+        rules.economy.lumber.every += 1;
+        assert_ne!(rules.hash(), Ruleset::default().hash());
+    }
+
+    #[test]
+    fn missing_fields_fall_back_to_defaults() {
+        let path = write_rules("partial", r#"{ "start_fuel": 10, "economy": { "gold": { "amount": 2.0 } } }"#);
+        let rules = Ruleset::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rules.start_fuel, 10);
+        assert_eq!(rules.economy.gold.amount, 2.0);
+        assert_eq!(rules.economy.gold.every, 1);
+        assert_eq!(rules.economy.lumber, Ruleset::default().economy.lumber);
+        assert_eq!(rules.items, Ruleset::default().items);
+    }
+
+    #[test]
+    fn load_rejects_a_wrong_type() {
+        let path = write_rules("wrong-type", r#"{ "start_fuel": "lots" }"#);
+        let result = Ruleset::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().starts_with(&path));
+    }
+
+    #[test]
+    fn defaults_without_a_file() {
+        let path = std::env::temp_dir().join("sc-ruleset-does-not-exist.json");
+        assert_eq!(Ruleset::load_or_default(path.to_str().unwrap()), Ok(Ruleset::default()));
+        assert!(Ruleset::load(path.to_str().unwrap()).is_err());
+    }
+}
@@ -2,8 +2,9 @@ use std::f32::EPSILON;
 
 use num_traits::{AsPrimitive, Num};
 use raylib::prelude::Vector2;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Rect<T: Num> {
     pub x: T,
     pub y: T,
@@ -0,0 +1,841 @@
+use std::collections::{HashMap, VecDeque};
+use std::cmp::{min, max};
+use std::hash::Hash;
+use std::ops::AddAssign;
+use raylib::prelude::Vector2;
+
+use crate::*;
+use crate::constants::AreaEnum;
+use crate::map::Map;
+use crate::ruleset::{Resource, Ruleset, Stat};
+
+pub fn rounded(v: Vector2) -> Vector2 {
+    Vector2::new(v.x.round(), v.y.round())
+}
+
+pub fn hm_add<K: Hash + Clone + Copy + Eq, V: AddAssign + Copy + Clone>(a: HashMap<K, V>, b: &HashMap<K, V>) -> HashMap<K, V> {
+    let mut out = a.clone();
+    for (k, v) in b.iter() {
+        out.entry(*k).and_modify(|e| *e += *v).or_insert(*v);
+    }
+    out
+}
+
+fn blink_unit(unit: &mut Unit, range: f32) -> () {
+    unit.blinking.iter_mut().for_each(|b| *b = false);
+    if (unit.path[0] - unit.pos).length() < range {
+        let mut acc = (unit.path[0] - unit.pos).length();
+        let mut p0 = unit.path.pop_front().unwrap();
+        while !unit.path.is_empty() {
+            let p1 = *unit.path.front().unwrap();
+            let l = (p1 - p0).length();
+            if l + acc >= range {
+                unit.pos = p0.lerp(p1, (range - acc)/l);
+                return;
+            }
+            acc += l;
+            p0 = p1;
+            unit.path.pop_front();
+        }
+        unit.pos = p0;
+    } else {
+        unit.pos += (unit.path[0] - unit.pos).normalized().scale_by(range)
+    }
+}
+
+fn move_unit(unit: &mut Unit, rules: &Ruleset, map: &Map) -> () {
+    let speed = unit.speed(rules, map);
+    unit.pos =
+        if (unit.path[0] - unit.pos).length() < speed {
+            // FIXME don't slow down on turns
+            unit.path[0]
+        } else {
+            unit.pos + (unit.path[0] - unit.pos).normalized().scale_by(speed)
+        };
+
+    if unit.pos == unit.path[0] {
+        unit.path.pop_front();
+    }
+}
+
+fn move_units(units: &mut Vec<Unit>, rules: &Ruleset, map: &Map) {
+    units.iter_mut().for_each(|unit| {
+        match unit.blinking {
+            Some(true) => blink_unit(unit, rules.blink_range),
+            _ => move_unit(unit, rules, map)
+        }
+        if let Some(z) = map.zone_at(unit.pos) {
+            if !unit.zones_crossed.contains(&z) {
+                unit.zones_crossed.push(z);
+            }
+        }
+    });
+}
+
+// To one of p_id's station tiles, along whole tiles and around blocked terrain
+fn valid_legs(game_state: &GameState, p_id: usize, path: &VecDeque<Vector2>) -> bool {
+    !path.is_empty() &&
+        game_state.map.station(p_id).contains(&path[path.len() - 1]) &&
+        path.iter().all(|p| game_state.map.area.contains_point(p) && rounded(*p) == *p) &&
+        path.iter().zip(path.iter().skip(1)).all(|(a, b)| (a.x == b.x || a.y == b.y) && !game_state.map.crosses_blocked(a, b))
+}
+
+// Ship to one of p_id's station tiles
+fn valid_path(game_state: &GameState, p_id: usize, path: &VecDeque<Vector2>) -> bool {
+    path.len() >= 2 && path[0] == *game_state.map.ship(p_id) && valid_legs(game_state, p_id, path)
+}
+
+// Index of the corner in unit.path that comes after p, None if p isn't on what is left of the unit's path
+pub fn join_index(unit: &Unit, p: Vector2) -> Option<usize> {
+    let between = |v: f32, s: f32, e: f32| v >= s.min(e) && v <= s.max(e);
+    let on = |a: Vector2, b: Vector2| (p.x == a.x && p.x == b.x && between(p.y, a.y, b.y)) ||
+        (p.y == a.y && p.y == b.y && between(p.x, a.x, b.x));
+    let mut prev = unit.pos;
+    for (k, next) in unit.path.iter().enumerate() {
+        if on(prev, *next) {
+            return Some(k);
+        }
+        prev = *next;
+    }
+    None
+}
+
+// Where the unit is and the path it follows after a TargetCommand, None if path doesn't work for the unit.
+// Moving keeps to the old path up to where path starts, blinking jumps straight to the start of path.
+pub fn retarget(unit: &Unit, target: Target, path: &VecDeque<Vector2>) -> Option<(Vector2, VecDeque<Vector2>)> {
+    let start = *path.front()?;
+    match target {
+        Target::Move => join_index(unit, start).map(|k| (unit.pos, unit.path.iter().take(k).chain(path.iter()).cloned().collect())),
+        Target::Blink => Some((start, path.clone())),
+    }
+}
+
+// Lumber a TargetCommand costs: the turns the unit's new path adds to what was left of its old one
+pub fn retarget_lumber_cost(unit: &Unit, (pos, path): &(Vector2, VecDeque<Vector2>)) -> i32 {
+    let from = |start: Vector2, p: &VecDeque<Vector2>| {
+        let mut full = p.clone();
+        full.push_front(start);
+        full
+    };
+    max(0, path_turns(&from(*pos, path)) - path_turns(&from(unit.pos, &unit.path)))
+}
+
+// Whether player p_id is allowed to issue command right now.
+pub fn validate_command(game_state: &GameState, p_id: usize, command: &GameCommand) -> bool {
+    match command {
+        GameCommand::Blink(BlinkCommand { u_id }) => {
+            game_state.unit(p_id, *u_id).map(|u| u.blink_cooldown <= 0 && u.blinking.is_some()).unwrap_or(false)
+        },
+        GameCommand::Spawn(SpawnMsgCommand { player_id, path }) => {
+            *player_id == p_id &&
+                game_state.spawn_cooldown[p_id] <= 0 &&
+                valid_path(game_state, p_id, path) &&
+                game_state.lumber[p_id] >= path_lumber_cost(path, &game_state.rules)
+        },
+        GameCommand::Target(TargetCommand { u_id, target, path }) => {
+            let can_target = |u: &Unit| match target {
+                Target::Move => u.blinking != Some(true),
+                // a blink ready to go that lands in range and not on blocked terrain
+                Target::Blink => u.blinking == Some(false) && u.blink_cooldown <= 0 &&
+                    path.front().map(|dest| (*dest - u.pos).length() <= game_state.rules.blink_range &&
+                        game_state.map.area_at(*dest) != Some(AreaEnum::Blocked)).unwrap_or(false),
+            };
+            match game_state.unit(p_id, *u_id) {
+                Some(u) => can_target(u) &&
+                    valid_legs(game_state, p_id, path) &&
+                    retarget(u, *target, path).map(|r| game_state.lumber[p_id] >= retarget_lumber_cost(u, &r)).unwrap_or(false),
+                None => false,
+            }
+        },
+        GameCommand::Intercept(InterceptCommand { pos }) => {
+            game_state.gold[p_id] >= game_state.rules.intercept_cost && game_state.map.area.contains_point(pos) && rounded(*pos) == *pos
+        },
+        GameCommand::Grapple(GrappleCommand { pos }) => {
+            game_state.gold[p_id] >= game_state.rules.grapple_cost &&
+                game_state.grapple_cooldown[p_id] <= 0 &&
+                !game_state.grapples.iter().any(|g| g.player_id == p_id) &&
+                rounded(*pos) == *pos &&
+                (*pos - *game_state.map.ship(p_id)).length() <= game_state.rules.grapple_range
+        },
+        GameCommand::BuyUpgrade(u) => {
+            !game_state.upgrades[p_id].contains(u) && game_state.gold[p_id] >= u.cost(&game_state.rules)
+        },
+        GameCommand::BuyItem(item) => {
+            game_state.item_count(p_id, *item) < item.max_stock(&game_state.rules) && game_state.gold[p_id] >= item.cost(&game_state.rules)
+        },
+        GameCommand::UseItem(item_use) => {
+            game_state.item_count(p_id, item_use.item()) > 0 && match item_use {
+                ItemUse::Decoy(SpawnMsgCommand { player_id, path }) => *player_id == p_id && valid_path(game_state, p_id, path),
+                ItemUse::Shield { u_id } => game_state.unit(p_id, *u_id).is_some(),
+                ItemUse::FuelCanister | ItemUse::Reveal => true,
+            }
+        },
+    }
+}
+
+fn spawn_unit(game_state: &mut GameState, player_id: usize, path: &VecDeque<Vector2>, decoy: bool) {
+    let id = game_state.next_unit_id;
+    game_state.next_unit_id += 1;
+    let unit = Unit {
+        id,
+        dead: false,
+        player_id,
+        pos: path[0],
+        path: path.clone(),
+        blinking: None,
+        blink_cooldown: 0,
+        carrying_bounty: HashMap::new(),
+        zones_crossed: vec![],
+        decoy,
+        shield: 0,
+    };
+    game_state.units[player_id].push(unit);
+}
+
+fn apply_updates(game_state: &mut GameState, updates: &[&Vec<GameCommand>], frame: i32) {
+    for i in 0..updates.len() {
+        for u in updates[i] {
+            // A command checked when it was queued can be stale by the frame it lands on (gold spent by
+            // an earlier command, unit already dead). Every peer runs this on the same state so a command
+            // dropped here is dropped everywhere and lockstep stays in sync.
+            if !validate_command(game_state, i, u) {
+                continue;
+            }
+            match u {
+                GameCommand::Blink(BlinkCommand { u_id }) => {
+                    let rules = &game_state.rules;
+                    if let Some(unit) = game_state.units[i].iter_mut().find(|u| u.id == *u_id) {
+                        unit.blink_cooldown = unit.cooldown(rules);
+                        unit.blinking = Some(true);
+                    }
+                },
+                GameCommand::Spawn(SpawnMsgCommand { path, player_id }) => {
+                    spawn_unit(game_state, *player_id, path, false);
+                    game_state.spawn_cooldown[*player_id] = game_state.rules.msg_cooldown;
+                    game_state.lumber[*player_id] -= path_lumber_cost(path, &game_state.rules);
+                },
+                GameCommand::Target(TargetCommand { u_id, target, path }) => {
+                    let rules = &game_state.rules;
+                    let lumber = &mut game_state.lumber;
+                    if let Some(unit) = game_state.units[i].iter_mut().find(|u| u.id == *u_id) {
+                        if let Some(r) = retarget(unit, *target, path) {
+                            lumber[i] -= retarget_lumber_cost(unit, &r);
+                            (unit.pos, unit.path) = r;
+                            if *target == Target::Blink {
+                                unit.blink_cooldown = unit.cooldown(rules);
+                            }
+                        }
+                    }
+                },
+                GameCommand::Intercept(InterceptCommand { pos }) => {
+                    let (delay, expiry, radius) = (game_state.stat(i, Stat::InterceptDelay), game_state.stat(i, Stat::InterceptExpiry), game_state.stat(i, Stat::InterceptRadius));
+                    game_state.interceptions.push(Interception { pos: pos.clone(), start_frame: frame, player_id: i, delay, expiry, radius });
+                    game_state.gold[i] -= game_state.rules.intercept_cost;
+                },
+                GameCommand::Grapple(GrappleCommand { pos }) => {
+                    game_state.grapples.push(Grapple { player_id: i, pos: *game_state.map.ship(i), target: *pos, returning: false, bounty: None });
+                    game_state.gold[i] -= game_state.rules.grapple_cost;
+                    game_state.grapple_cooldown[i] = game_state.rules.grapple_cooldown;
+                },
+                GameCommand::BuyUpgrade(u) => {
+                    game_state.upgrades[i].insert(*u);
+                    game_state.gold[i] -= u.cost(&game_state.rules);
+                },
+                GameCommand::BuyItem(item) => {
+                    game_state.items[i].entry(*item).and_modify(|e| *e += 1).or_insert(1);
+                    game_state.gold[i] -= item.cost(&game_state.rules);
+                },
+                GameCommand::UseItem(item_use) => {
+                    game_state.items[i].entry(item_use.item()).and_modify(|e| *e -= 1);
+                    let items = &game_state.rules.items;
+                    match item_use {
+                        ItemUse::Decoy(SpawnMsgCommand { path, player_id }) => {
+                            spawn_unit(game_state, *player_id, path, true);
+                        },
+                        ItemUse::FuelCanister => {
+                            let amount = min(items.canister_fuel, max(0, game_state.rules.start_fuel - game_state.fuel[i]));
+                            earn(game_state, i, Resource::Fuel, amount as f32);
+                        },
+                        ItemUse::Shield { u_id } => {
+                            let shield_duration = items.shield_duration;
+                            if let Some(unit) = game_state.unit_mut(i, *u_id) {
+                                unit.shield = shield_duration;
+                            }
+                        },
+                        ItemUse::Reveal => {
+                            game_state.reveal[i] = items.reveal_duration;
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    let teams = &game_state.teams;
+    let mut kills = vec![];
+    for intercept in &game_state.interceptions {
+        if frame - intercept.start_frame >= intercept.delay {
+            let enemy_units = game_state.units.iter_mut().flatten()
+                .filter(|u| teams[u.player_id] != teams[intercept.player_id]);
+            for unit in enemy_units {
+                // Have to check unit.dead to avoid double counting interception kills (If 2 interceptions kill the same unit on the same frame)
+                if !unit.dead && unit.shield <= 0 {
+                    if intercept.covers(rounded(unit.pos)) {
+                        unit.dead = true;
+                        // decoys die like messages but don't count as kills
+                        if !unit.decoy {
+                            game_state.intercepted[intercept.player_id] += 1;
+                            game_state.lost[unit.player_id] += 1;
+                            kills.push(intercept.player_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    game_state.interceptions.retain(|i| (frame - i.start_frame) < i.expiry + i.delay);
+    reap(game_state);
+    for p_id in kills {
+        for r in Resource::ALL {
+            let amount = game_state.rules.economy.get(r).per_kill;
+            if amount != 0.0 {
+                earn(game_state, p_id, r, amount);
+            }
+        }
+    }
+}
+
+fn apply_bounties(game_state: &mut GameState, p_id: usize, bounties: HashMap<BountyEnum, i32>) {
+    for (b_type, amt) in bounties.iter() {
+        let r = match *b_type {
+            BountyEnum::Fuel => Resource::Fuel,
+            BountyEnum::Gold => Resource::Gold,
+            BountyEnum::Lumber => Resource::Lumber,
+            _ => continue,
+        };
+        earn(game_state, p_id, r, *amt as f32);
+    }
+}
+
+pub fn same_tile(a: Vector2, b: Vector2) -> bool {
+    a.x.round() == b.x.round() && a.y.round() == b.y.round()
+}
+
+// Adds amount of income to a player's resource. Positive amounts stop at the income's cap, but never take away
+// what the player already had above it.
+fn earn(game_state: &mut GameState, p_id: usize, resource: Resource, amount: f32) {
+    let cap = game_state.rules.economy.get(resource).cap;
+    let add = |v: f32| match cap {
+        Some(c) if amount > 0.0 && v + amount > c => v.max(c),
+        _ => v + amount,
+    };
+    match resource {
+        Resource::Fuel => game_state.fuel[p_id] = add(game_state.fuel[p_id] as f32).round() as i32,
+        Resource::Gold => game_state.gold[p_id] = add(game_state.gold[p_id]),
+        Resource::Lumber => game_state.lumber[p_id] = add(game_state.lumber[p_id] as f32).round() as i32,
+    }
+}
+
+fn resource(game_state: &GameState, p_id: usize, resource: Resource) -> f32 {
+    match resource {
+        Resource::Fuel => game_state.fuel[p_id] as f32,
+        Resource::Gold => game_state.gold[p_id],
+        Resource::Lumber => game_state.lumber[p_id] as f32,
+    }
+}
+
+fn deliver_messages(game_state: &mut GameState) {
+    let players = game_state.players();
+
+    let mut delivered = vec![0; players];
+    // extra fuel for the zones the delivered units crossed
+    let mut zone_fuel = vec![0; players];
+    let mut bounties = vec![HashMap::new(); players];
+    for u in game_state.units.iter_mut().flatten() {
+        if game_state.map.station(u.player_id).iter().any(|s| same_tile(u.pos, *s)) {
+            u.dead = true;
+            // decoys deliver nothing, not even the bounties they picked up
+            if u.decoy {
+                continue;
+            }
+            delivered[u.player_id] += 1;
+            zone_fuel[u.player_id] += u.zones_crossed.iter().map(|z| game_state.rules.zones.get(*z).delivery_fuel).sum::<i32>();
+            bounties[u.player_id] = hm_add(std::mem::take(&mut bounties[u.player_id]), &u.carrying_bounty);
+        }
+    }
+    reap(game_state);
+
+    for (p_id, b) in bounties.into_iter().enumerate() {
+        game_state.delivered[p_id] += delivered[p_id];
+        apply_bounties(game_state, p_id, b);
+        for r in Resource::ALL {
+            let mut amount = delivered[p_id] as f32 * game_state.rules.economy.get(r).per_delivery;
+            if r == Resource::Fuel {
+                amount += zone_fuel[p_id] as f32;
+            }
+            if amount != 0.0 {
+                earn(game_state, p_id, r, amount);
+            }
+        }
+    }
+}
+
+// Hooks grab the bounty on the target tile when they get there, it's credited once they are back at the ship.
+// Blink bounties need a unit to carry them so hooks leave them be.
+fn move_grapples(game_state: &mut GameState) {
+    let speed = game_state.rules.grapple_speed;
+    let mut credit = vec![];
+    for g in game_state.grapples.iter_mut() {
+        let dest = if g.returning { *game_state.map.ship(g.player_id) } else { g.target };
+        g.pos = if (dest - g.pos).length() <= speed { dest } else { g.pos + (dest - g.pos).normalized().scale_by(speed) };
+        if g.pos != dest {
+            continue;
+        }
+        if g.returning {
+            if let Some(b) = g.bounty {
+                credit.push((g.player_id, b));
+            }
+        } else {
+            if let Some(i) = game_state.bounties.iter().position(|b| b.type_ != BountyEnum::Blink && same_tile(b.pos, g.target)) {
+                g.bounty = Some(game_state.bounties.remove(i));
+            }
+            g.returning = true;
+        }
+    }
+    game_state.grapples.retain(|g| !(g.returning && g.pos == *game_state.map.ship(g.player_id)));
+    for (p_id, b) in credit {
+        apply_bounties(game_state, p_id, HashMap::from([(b.type_, b.amount)]));
+    }
+}
+
+fn tick(game_state: &mut GameState) {
+    for u in game_state.units.iter_mut().flatten() {
+        u.blink_cooldown = max(0, u.blink_cooldown - 1);
+        u.shield = max(0, u.shield - 1);
+    }
+
+    let zone_fuel: Vec<(usize, i32)> = game_state.units.iter().flatten()
+        .filter_map(|u| game_state.map.zone_at(u.pos).map(|z| (u.player_id, game_state.rules.zones.get(z).fuel_per_frame)))
+        .collect();
+    for (p_id, amount) in zone_fuel {
+        if amount != 0 {
+            earn(game_state, p_id, Resource::Fuel, amount as f32);
+        }
+    }
+
+    for r in Resource::ALL {
+        let income = game_state.rules.economy.get(r).clone();
+        if income.amount == 0.0 || game_state.frame % income.every.max(1) != 0 {
+            continue;
+        }
+        for p_id in 0..game_state.players() {
+            if let Some(below) = income.below {
+                if resource(game_state, p_id, r) >= below {
+                    continue;
+                }
+            }
+            earn(game_state, p_id, r, income.amount);
+        }
+    }
+    game_state.spawn_cooldown.iter_mut().for_each(|s| *s = max(*s - 1, 0));
+    game_state.reveal.iter_mut().for_each(|r| *r = max(*r - 1, 0));
+    game_state.grapple_cooldown.iter_mut().for_each(|g| *g = max(*g - 1, 0));
+}
+
+fn reap(game_state: &mut GameState) {
+    for units in game_state.units.iter_mut() {
+        units.retain(|u| !u.dead);
+    }
+}
+
+fn no_hmap_units(units: &Vec<Unit>) -> Vec<Unit> {
+    units.iter().map(|u| Unit { carrying_bounty: HashMap::new(), ..u.clone() }).collect()
+}
+
+pub fn serialize_state(game_state: &GameState) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    // FIXME serialize units.carrying_bounty
+    let units: Vec<Unit> = game_state.all_units().cloned().collect();
+    let mut v = rmp_serde::encode::to_vec(&no_hmap_units(&units))?;
+    v.append(&mut rmp_serde::encode::to_vec(&game_state.fuel)?);
+    v.append(&mut rmp_serde::encode::to_vec(&game_state.intercepted)?);
+    v.append(&mut rmp_serde::encode::to_vec(&game_state.gold)?);
+    // FIXME serialize upgrades and items correctly (easiest might be to convert to sorted vec and serialize)
+    let upg: Vec<usize> = game_state.upgrades.iter().map(|hs| hs.len()).collect();
+    v.append(&mut rmp_serde::encode::to_vec(&upg)?);
+    v.append(&mut rmp_serde::encode::to_vec(&game_state.bounties)?);
+    v.append(&mut rmp_serde::encode::to_vec(&game_state.next_bounty)?);
+    Ok(v)
+}
+
+fn collide_bounties(game_state: &mut GameState) {
+    let pack_bounty = |m_unit: Option<&mut Unit>, b: &Bounty| {
+        if let Some(unit) = m_unit {
+            if b.type_ == BountyEnum::Blink {
+                unit.blink_cooldown = 0;
+                if unit.blinking.is_none() {
+                    unit.blinking = Some(false);
+                }
+            }
+            unit.carrying_bounty.entry(b.type_).and_modify(|e| *e += b.amount).or_insert(b.amount);
+        } 
+    };
+
+    // every player gets a copy, but only one of their units picks it up
+    for b in &game_state.bounties {
+        for p_id in 0..game_state.teams.len() {
+            let m_unit = game_state.units[p_id].iter_mut().find(|u| same_tile(u.pos, b.pos));
+            pack_bounty(m_unit, b);
+        }
+    }
+
+    // PERF loop only once
+    game_state.bounties.retain(|b| !game_state.units.iter().flatten().any(|u| same_tile(u.pos, b.pos)))
+}
+
+// Changes of direction along path, zero length segments don't count
+pub fn path_turns(path: &VecDeque<Vector2>) -> i32 {
+    let dirs: Vec<Vector2> = path.iter().zip(path.iter().skip(1)).filter(|(a, b)| a != b).map(|(a, b)| (*b - *a).normalized()).collect();
+    dirs.iter().zip(dirs.iter().skip(1)).filter(|(a, b)| a != b).count() as i32
+}
+
+pub fn path_lumber_cost(path: &VecDeque<Vector2>, rules: &Ruleset) -> i32 {
+    max(0, path_turns(path) - rules.msg_free_lumber)
+}
+
+// Advances the simulation by one frame. updates are indexed by player id.
+pub fn step(game_state: &mut GameState, updates: &[&Vec<GameCommand>], frame: i32) {
+    game_state.frame = frame;
+    apply_updates(game_state, updates, frame);
+    director::update(game_state);
+    for units in game_state.units.iter_mut() {
+        move_units(units, &game_state.rules, &game_state.map);
+    }
+    deliver_messages(game_state);
+    move_grapples(game_state);
+    collide_bounties(game_state);
+    tick(game_state);
+}
+
+// None while the game is still running, otherwise Some(winner) where winner is a team id and None is a draw.
+// A team is out once all its players ran out of fuel, kills count for the whole team.
+pub fn game_over(game_state: &GameState) -> Option<Option<usize>> {
+    let kills_to_win = game_state.rules.kills_to_win as u32;
+    let mut teams = game_state.teams.clone();
+    teams.sort();
+    teams.dedup();
+    let members = |t: usize| (0..game_state.players()).filter(move |p| game_state.teams[*p] == t);
+    let kills = |t: usize| members(t).map(|p| game_state.intercepted[p] as u32).sum::<u32>();
+    let alive: Vec<usize> = teams.iter().copied().filter(|t| members(*t).any(|p| game_state.fuel[p] > 0)).collect();
+    let winners: Vec<usize> = alive.iter().copied().filter(|t| kills(*t) >= kills_to_win).collect();
+
+    if teams.iter().all(|t| kills(*t) >= kills_to_win) || alive.is_empty() {
+        Some(None)
+    } else if alive.len() == 1 {
+        Some(Some(alive[0]))
+    } else if winners.len() == 1 {
+        Some(Some(winners[0]))
+    } else if winners.len() > 1 {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use crate::map::{MapFile, Zone, ZoneKind};
+    use crate::ruleset::Income;
+    use crate::shapes::Rect;
+    use super::*;
+
+    fn state(p_id: usize) -> GameState {
+        GameState::new(p_id, ChaCha20Rng::seed_from_u64(0), Ruleset::default(), Map::default(), Mode::Ffa)
+    }
+
+    // a ship in every corner, teams are 0 and 2 against 1 and 3
+    fn four_players(mode: Mode) -> GameState {
+        let file = MapFile {
+            area: Rect { x: 0, y: 0, w: 8, h: 8 },
+            ships: vec![[0, 0], [8, 8], [0, 8], [8, 0]],
+            stations: vec![vec![[4, 0]], vec![[4, 8]], vec![[0, 4]], vec![[8, 4]]],
+            blocked: vec![],
+            bounty_zones: vec![],
+            zones: vec![],
+        };
+        GameState::new(0, ChaCha20Rng::seed_from_u64(0), Ruleset::default(), Map::from_file("four", file).unwrap(), mode)
+    }
+
+    #[test]
+    fn stale_commands_are_dropped_the_same_on_every_peer() {
+        // the same match seen from both players
+        let mut peers = [state(0), state(1)];
+        let intercept = |x: f32| GameCommand::Intercept(InterceptCommand { pos: Vector2::new(x, 0.0) });
+        // both were affordable when they were queued but there is only gold for one
+        let updates = vec![intercept(0.0), intercept(1.0)];
+        let none = vec![];
+        for game_state in peers.iter_mut() {
+            game_state.gold[0] = game_state.rules.intercept_cost;
+            step(game_state, &[&updates, &none], 0);
+            assert_eq!(game_state.interceptions.len(), 1);
+            assert_eq!(game_state.gold[0], 0.0);
+        }
+        assert_eq!(serialize_state(&peers[0]).unwrap(), serialize_state(&peers[1]).unwrap());
+    }
+
+    #[test]
+    fn zone_fuel_is_paid_by_the_owner_of_the_unit_inside() {
+        let mut game_state = state(0);
+        game_state.map.zones = vec![Zone { kind: ZoneKind::Nebula, area: Rect { x: 0, y: 0, w: 2, h: 2 } }];
+        let drain = game_state.rules.zones.nebula.fuel_per_frame;
+        assert!(drain < 0);
+        spawn_unit(&mut game_state, 0, &VecDeque::from(vec![Vector2::new(1.0, 1.0), Vector2::new(1.0, 5.0)]), false);
+        spawn_unit(&mut game_state, 1, &VecDeque::from(vec![Vector2::new(4.0, 1.0), Vector2::new(4.0, 5.0)]), false);
+        let before = game_state.fuel.clone();
+        tick(&mut game_state);
+        let income = game_state.rules.economy.fuel.amount as i32;
+        assert_eq!(game_state.fuel[0], before[0] + income + drain);
+        assert_eq!(game_state.fuel[1], before[1] + income);
+    }
+
+    #[test]
+    fn team_is_out_once_every_member_is_out_of_fuel() {
+        let mut game_state = four_players(Mode::Teams);
+        game_state.fuel[0] = 0;
+        assert_eq!(game_over(&game_state), None);
+        game_state.fuel[2] = 0;
+        assert_eq!(game_over(&game_state), Some(Some(1)));
+    }
+
+    #[test]
+    fn team_kills_add_up() {
+        let mut game_state = four_players(Mode::Teams);
+        let kills_to_win = game_state.rules.kills_to_win;
+        game_state.intercepted[1] = kills_to_win - 1;
+        game_state.intercepted[3] = 1;
+        assert_eq!(game_over(&game_state), Some(Some(1)));
+        // the same kills don't win anything on their own
+        let mut game_state = four_players(Mode::Ffa);
+        game_state.intercepted[1] = kills_to_win - 1;
+        game_state.intercepted[3] = 1;
+        assert_eq!(game_over(&game_state), None);
+    }
+
+    fn line(x: f32) -> VecDeque<Vector2> {
+        VecDeque::from(vec![Vector2::new(x, 0.0), Vector2::new(x, 5.0)])
+    }
+
+    #[test]
+    fn buying_stops_at_max_stock() {
+        let mut game_state = state(0);
+        game_state.gold[0] = 1000.0;
+        let reveal = game_state.rules.items.reveal.clone();
+        assert_eq!(reveal.max_stock, 1);
+        let updates = vec![GameCommand::BuyItem(Item::Reveal), GameCommand::BuyItem(Item::Reveal)];
+        apply_updates(&mut game_state, &[&updates, &vec![]], 0);
+        assert_eq!(game_state.item_count(0, Item::Reveal), 1);
+        assert_eq!(game_state.gold[0], 1000.0 - reveal.cost);
+        assert!(!validate_command(&game_state, 0, &GameCommand::BuyItem(Item::Reveal)));
+    }
+
+    #[test]
+    fn items_are_used_up() {
+        let mut game_state = state(0);
+        let reveal = vec![GameCommand::UseItem(ItemUse::Reveal)];
+        assert!(!validate_command(&game_state, 0, &reveal[0]));
+        game_state.items[0].insert(Item::Reveal, 1);
+        apply_updates(&mut game_state, &[&reveal, &vec![]], 0);
+        assert_eq!(game_state.reveal[0], game_state.rules.items.reveal_duration);
+        assert_eq!(game_state.item_count(0, Item::Reveal), 0);
+        assert!(!validate_command(&game_state, 0, &reveal[0]));
+    }
+
+    #[test]
+    fn shield_survives_an_interception() {
+        let mut game_state = state(0);
+        spawn_unit(&mut game_state, 1, &line(0.0), false);
+        spawn_unit(&mut game_state, 1, &line(0.0), false);
+        game_state.unit_mut(1, 0).unwrap().shield = 10;
+        game_state.interceptions.push(Interception { start_frame: 0, pos: Vector2::new(0.0, 0.0), player_id: 0, delay: 0, expiry: 10, radius: 0 });
+        apply_updates(&mut game_state, &[&vec![], &vec![]], 0);
+        assert!(game_state.unit(1, 0).is_some());
+        assert!(game_state.unit(1, 1).is_none());
+        assert_eq!(game_state.intercepted[0], 1);
+    }
+
+    #[test]
+    fn decoys_deliver_nothing() {
+        let mut game_state = state(0);
+        let station = VecDeque::from(vec![game_state.map.station(0)[0]]);
+        spawn_unit(&mut game_state, 0, &station, true);
+        game_state.unit_mut(0, 0).unwrap().carrying_bounty.insert(BountyEnum::Gold, 50);
+        let gold = game_state.gold[0];
+        deliver_messages(&mut game_state);
+        assert!(game_state.units(0).is_empty());
+        assert_eq!(game_state.delivered[0], 0);
+        assert_eq!(game_state.gold[0], gold);
+        // a real one delivers
+        spawn_unit(&mut game_state, 0, &station, false);
+        deliver_messages(&mut game_state);
+        assert_eq!(game_state.delivered[0], 1);
+    }
+
+    // grapples a bounty of b_type three tiles from player 0's ship and runs the hook until it is back
+    fn grapple(b_type: BountyEnum) -> GameState {
+        let mut game_state = state(0);
+        let target = *game_state.map.ship(0) + Vector2::new(3.0, 0.0);
+        game_state.bounties = vec![Bounty { type_: b_type, amount: 50, pos: target }];
+        let command = vec![GameCommand::Grapple(GrappleCommand { pos: target })];
+        assert!(validate_command(&game_state, 0, &command[0]));
+        apply_updates(&mut game_state, &[&command, &vec![]], 0);
+        for _ in 0..120 {
+            move_grapples(&mut game_state);
+        }
+        assert!(game_state.grapples.is_empty());
+        game_state
+    }
+
+    #[test]
+    fn grapple_credits_the_bounty_once_back() {
+        let gold = state(0).gold[0] - state(0).rules.grapple_cost;
+        let game_state = grapple(BountyEnum::Gold);
+        assert!(game_state.bounties.is_empty());
+        assert_eq!(game_state.gold[0], gold + 50.0);
+    }
+
+    #[test]
+    fn grapple_leaves_blink_bounties() {
+        let game_state = grapple(BountyEnum::Blink);
+        assert_eq!(game_state.bounties.len(), 1);
+    }
+
+    #[test]
+    fn grapple_rejects_out_of_range_and_a_second_hook() {
+        let mut game_state = state(0);
+        let range = game_state.rules.grapple_range;
+        let far = GameCommand::Grapple(GrappleCommand { pos: *game_state.map.ship(0) + Vector2::new(0.0, -(range + 1.0)) });
+        assert!(!validate_command(&game_state, 0, &far));
+        let near = vec![GameCommand::Grapple(GrappleCommand { pos: *game_state.map.ship(0) + Vector2::new(0.0, -1.0) })];
+        apply_updates(&mut game_state, &[&near, &vec![]], 0);
+        game_state.grapple_cooldown[0] = 0;
+        assert!(!validate_command(&game_state, 0, &near[0]));
+    }
+
+    #[test]
+    fn income_pays_every_n_frames() {
+        let mut game_state = state(0);
+        game_state.rules.economy.gold = Income { amount: 2.0, every: 3, ..Income::default() };
+        let gold = game_state.gold[0];
+        for frame in 0..6 {
+            game_state.frame = frame;
+            tick(&mut game_state);
+        }
+        // frames 0 and 3
+        assert_eq!(game_state.gold[0], gold + 4.0);
+    }
+
+    #[test]
+    fn income_stops_at_below() {
+        let mut game_state = state(0);
+        let lumber = game_state.rules.economy.lumber.clone();
+        let below = lumber.below.unwrap() as i32;
+        game_state.lumber = vec![below - 1, below];
+        tick(&mut game_state);
+        assert_eq!(game_state.lumber, vec![below - 1 + lumber.amount as i32, below]);
+    }
+
+    #[test]
+    fn deliveries_and_kills_pay_their_income() {
+        let mut game_state = state(0);
+        game_state.rules.economy.gold = Income { per_delivery: 10.0, per_kill: 7.0, ..Income::default() };
+        let gold = game_state.gold[0];
+        let station = VecDeque::from(vec![game_state.map.station(0)[0]]);
+        spawn_unit(&mut game_state, 0, &station, false);
+        deliver_messages(&mut game_state);
+        assert_eq!(game_state.gold[0], gold + 10.0);
+        spawn_unit(&mut game_state, 1, &line(0.0), false);
+        game_state.interceptions.push(Interception { start_frame: 0, pos: Vector2::new(0.0, 0.0), player_id: 0, delay: 0, expiry: 10, radius: 0 });
+        apply_updates(&mut game_state, &[&vec![], &vec![]], 0);
+        assert_eq!(game_state.gold[0], gold + 17.0);
+    }
+
+    fn path(points: &[(f32, f32)]) -> VecDeque<Vector2> {
+        points.iter().map(|(x, y)| Vector2::new(*x, *y)).collect()
+    }
+
+    // player 0's unit 0, still on its ship, heading up the left edge and along the top to the station
+    fn messenger() -> GameState {
+        let mut game_state = state(0);
+        spawn_unit(&mut game_state, 0, &path(&[(-12.0, 11.0), (-12.0, -12.0), (11.0, -12.0)]), false);
+        game_state
+    }
+
+    #[test]
+    fn reroute_keeps_the_path_up_to_where_it_branches_off() {
+        let mut game_state = messenger();
+        let lumber = game_state.lumber[0];
+        let reroute = vec![GameCommand::Target(TargetCommand { u_id: 0, target: Target::Move, path: path(&[(-12.0, 0.0), (8.0, 0.0), (8.0, -12.0)]) })];
+        assert!(validate_command(&game_state, 0, &reroute[0]));
+        apply_updates(&mut game_state, &[&reroute, &vec![]], 0);
+        let unit = game_state.unit(0, 0).unwrap();
+        assert_eq!(unit.path, path(&[(-12.0, 11.0), (-12.0, 0.0), (8.0, 0.0), (8.0, -12.0)]));
+        // one turn more than before
+        assert_eq!(game_state.lumber[0], lumber - 1);
+    }
+
+    #[test]
+    fn reroute_rejects_a_path_off_the_units_path() {
+        let mut game_state = messenger();
+        let off = GameCommand::Target(TargetCommand { u_id: 0, target: Target::Move, path: path(&[(0.0, 0.0), (8.0, 0.0), (8.0, -12.0)]) });
+        assert!(!validate_command(&game_state, 0, &off));
+        let on = GameCommand::Target(TargetCommand { u_id: 0, target: Target::Move, path: path(&[(-12.0, 0.0), (8.0, 0.0), (8.0, -12.0)]) });
+        // nor while the unit is mid blink
+        game_state.unit_mut(0, 0).unwrap().blinking = Some(true);
+        assert!(!validate_command(&game_state, 0, &on));
+    }
+
+    #[test]
+    fn blink_lands_on_the_target_tile() {
+        let mut game_state = messenger();
+        game_state.unit_mut(0, 0).unwrap().blinking = Some(false);
+        let blink = vec![GameCommand::Target(TargetCommand { u_id: 0, target: Target::Blink, path: path(&[(-10.0, 10.0), (-10.0, -12.0), (11.0, -12.0)]) })];
+        assert!(validate_command(&game_state, 0, &blink[0]));
+        apply_updates(&mut game_state, &[&blink, &vec![]], 0);
+        let unit = game_state.unit(0, 0).unwrap();
+        assert_eq!(unit.pos, Vector2::new(-10.0, 10.0));
+        assert_eq!(unit.blink_cooldown, game_state.rules.blink_cooldown);
+    }
+
+    #[test]
+    fn blink_rejects_out_of_range_blocked_and_unready() {
+        let mut game_state = messenger();
+        let blink = |x: f32, y: f32| GameCommand::Target(TargetCommand { u_id: 0, target: Target::Blink, path: path(&[(x, y), (x, -12.0), (11.0, -12.0)]) });
+        // no blink picked up yet
+        assert!(!validate_command(&game_state, 0, &blink(-10.0, 10.0)));
+        game_state.unit_mut(0, 0).unwrap().blinking = Some(false);
+        assert!(validate_command(&game_state, 0, &blink(-10.0, 10.0)));
+        assert!(!validate_command(&game_state, 0, &blink(-6.0, 11.0)));
+        game_state.map.blocked = vec![Vector2::new(-10.0, 10.0)];
+        assert!(!validate_command(&game_state, 0, &blink(-10.0, 10.0)));
+        game_state.map.blocked.clear();
+        game_state.unit_mut(0, 0).unwrap().blink_cooldown = 1;
+        assert!(!validate_command(&game_state, 0, &blink(-10.0, 10.0)));
+    }
+
+    #[test]
+    fn bounties_stop_at_the_cap() {
+        let mut game_state = state(0);
+        let cap = game_state.rules.economy.fuel.cap.unwrap() as i32;
+        game_state.fuel[0] = 0;
+        apply_bounties(&mut game_state, 0, HashMap::from([(BountyEnum::Fuel, 100)]));
+        assert_eq!(game_state.fuel[0], 100);
+        game_state.fuel[0] = cap - 10;
+        apply_bounties(&mut game_state, 0, HashMap::from([(BountyEnum::Fuel, 100)]));
+        assert_eq!(game_state.fuel[0], cap);
+        // but never take away what is already above it
+        game_state.fuel[1] = cap + 50;
+        apply_bounties(&mut game_state, 1, HashMap::from([(BountyEnum::Fuel, 100)]));
+        assert_eq!(game_state.fuel[1], cap + 50);
+    }
+}